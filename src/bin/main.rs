@@ -1,73 +1,46 @@
+use chrono::{DateTime, Local};
 use clap::Parser;
 use color_eyre::eyre::eyre;
-use log::{info, LevelFilter};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::Mutex;
+use log::{debug, error, info, trace, warn};
+use std::{
+	io::{stdout, BufRead, IsTerminal},
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{self, UnboundedSender};
 
 #[cfg(all(unix, feature = "drop_privs"))]
 use privdrop::PrivDrop;
 
-use rspamd_mon::counters::RspamdStat;
-
-#[derive(Clone, Debug, Parser, Default)]
-#[clap(rename_all = "kebab-case")]
-pub(crate) struct PlotOptions {
-	/// Chart height.
-	#[clap(long, default_value = "6")]
-	chart_height: usize,
-}
-
-#[derive(Clone, Debug, Parser, Default)]
-#[clap(rename_all = "kebab-case")]
-pub(crate) struct PrometheusOptions {
-	/// Prometheus endpoint port.
-	#[clap(long, default_value = "65432")]
-	port: u16,
-}
-
-#[derive(Clone, Debug, Parser)]
-#[clap(rename_all = "kebab-case")]
-pub(crate) enum CliMode {
-	/// CLI chart mode.
-	Plot(PlotOptions),
-	/// Prometheus endpoint mode.
-	Prometheus(PrometheusOptions),
-}
-
-#[derive(Debug, Parser)]
-pub(crate) struct CliOpts {
-	#[clap(name = "url", long, default_value = "http://localhost:11334/stat")]
-	url: String,
-	/// Verbosity level: -v - info, -vv - debug, -vvv - trace
-	#[clap(short = 'v', long, parse(from_occurrences))]
-	verbose: i8,
-	/// How often do we poll Rspamd
-	#[clap(long, default_value = "1.0")]
-	timeout: f32,
-	/// Elements to store (and display)
-	#[clap(long, default_value = "80")]
-	num_elements: usize,
-	#[clap(flatten)]
-	#[cfg(all(unix, feature = "drop_privs"))]
-	privdrop: PrivDropConfig,
-	#[clap(subcommand)]
-	mode: CliMode,
-}
-
 #[cfg(all(unix, feature = "drop_privs"))]
-#[derive(Debug, Parser, Clone, Default)]
-#[clap(rename_all = "kebab-case")]
-struct PrivDropConfig {
-	/// Run as this user and their primary group
-	#[clap(short = 'u', long)]
-	user: Option<String>,
-	/// Run as this group
-	#[clap(short = 'g', long)]
-	group: Option<String>,
-	/// Chroot to this directory
-	#[clap(long)]
-	chroot: Option<String>,
-}
+use rspamd_mon::cli::PrivDropConfig;
+use rspamd_mon::{
+	cli::{CliMode, CliOpts, PlotOptions},
+	client::{
+		build_client, fetch_instances, fetch_stat, instance_label, split_credentials, statreset_url, wait_for_ready,
+		ClientSettings, Endpoint, Failover,
+	},
+	counters::{display_stacked_plot, raw_summary, ActionMap, RspamdStat},
+	csv::CsvWriter,
+	diagnostics::{consistency_check, status_line, ExitStatus},
+	health::HealthWeights,
+	json::JsonWriter,
+	logging::{logger, ErrorThrottle},
+	plot::{
+		enter_term, fit_chart_height, fit_chart_width, restore_term, term_cols, term_rows, PlotSettings,
+		COMPACT_CAPTION_COLUMNS,
+	},
+	poll::{run_tick, should_render, slow_start_interval, Backoff, Clock, IntervalDrift, SampleClock, SystemClock},
+	prometheus::serve,
+	pushgateway::Pushgateway,
+	replay::{Replayer, SampleReader},
+	shared::SharedStat,
+	statsd::StatsdSender,
+};
 
 fn drop_privs(privdrop: &PrivDropConfig) {
 	#[cfg(all(unix, feature = "drop_privs"))]
@@ -95,87 +68,593 @@ fn drop_privs(privdrop: &PrivDropConfig) {
 	}
 }
 
-const MAX_NET_ERRORS: i32 = 5;
+/// Requests an event marker on the charts each time `m` is entered, the poller adds it on the next tick
+fn read_markers(marker_requested: Arc<AtomicBool>) {
+	std::thread::spawn(move || {
+		for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+			if line.trim() == "m" {
+				marker_requested.store(true, Ordering::Relaxed);
+			}
+		}
+	});
+}
+
+/// Checks whether all charts fit into the terminal, reducing their height if needed
+fn fit_plot_height(plot_opts: &PlotOptions, charts: usize) -> u16 {
+	let height = plot_opts.chart_height as u16;
+	// A focused chart takes the whole terminal anyway
+	let charts = if plot_opts.focus.is_some() { 1 } else { plot_opts.charts_per_page.unwrap_or(charts).min(charts) };
+
+	match term_rows() {
+		Some(rows) => match fit_chart_height(charts, height, rows) {
+			Some(fitted) if fitted < height => {
+				warn!(
+					"{} charts of height {} do not fit into {} rows, reducing height to {}",
+					charts, height, rows, fitted
+				);
+				fitted
+			},
+			Some(fitted) => fitted,
+			None => {
+				warn!("{} charts do not fit into {} rows, consider using --charts-per-page or --focus", charts, rows);
+				height
+			},
+		},
+		None => height,
+	}
+}
+
+/// Builds settings of the next frame, the chart width follows the current terminal width
+fn plot_settings(plot_opts: &PlotOptions, height: u16, page: usize) -> PlotSettings {
+	let cols = term_cols();
+
+	PlotSettings {
+		height,
+		log_scale: plot_opts.log_scale,
+		focus: plot_opts.focus.clone(),
+		window_summary: plot_opts.window_summary,
+		round: plot_opts.round,
+		line_colors: plot_opts.line_colors,
+		charts_per_page: plot_opts.charts_per_page,
+		page,
+		health: plot_opts.health,
+		correlate: plot_opts.correlate.clone(),
+		caption_fields: plot_opts.caption_fields,
+		smooth_total: plot_opts.smooth_total,
+		smooth: plot_opts.smooth,
+		compact_caption: plot_opts.compact_caption || cols.is_some_and(|cols| cols < COMPACT_CAPTION_COLUMNS),
+		width: cols.map(fit_chart_width),
+		slo_scan_time: plot_opts.slo_scan_time,
+		no_color: !plot_opts.color.enabled(no_color_env(), stdout().is_terminal()),
+	}
+}
+
+/// Whether `NO_COLOR` is set to a non-empty value, see https://no-color.org
+fn no_color_env() -> bool {
+	std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())
+}
+
+/// Notifies about terminal resizes, so charts are redrawn at once instead of on the next poll
+fn watch_resize(resized: UnboundedSender<()>) {
+	#[cfg(unix)]
+	tokio::spawn(async move {
+		use tokio::signal::unix::{signal, SignalKind};
+
+		match signal(SignalKind::window_change()) {
+			Ok(mut resizes) => {
+				while resizes.recv().await.is_some() {
+					if resized.send(()).is_err() {
+						break;
+					}
+				}
+			},
+			Err(e) => debug!("cannot watch terminal resizes: {}", e),
+		}
+	});
+	#[cfg(not(unix))]
+	drop(resized);
+}
+
+fn client_settings(opts: &CliOpts, password: Option<String>) -> ClientSettings {
+	ClientSettings {
+		timeout: opts.request_timeout(),
+		connect_timeout: opts.connect_timeout,
+		tls_min_version: opts.tls_min_version,
+		host_header: opts.host_header.clone(),
+		password,
+		socks5: opts.socks5.clone(),
+		retries: opts.tick_retries,
+		client_cert: opts.client_cert.clone(),
+		client_key: opts.client_key.clone(),
+		ca_cert: opts.ca_cert.clone(),
+		insecure: opts.insecure,
+		anonymize: opts.anonymize_instance,
+	}
+}
+
+/// Polls every instance once and prints its raw counters, fails if any of them cannot be polled
+async fn poll_once(opts: &CliOpts, instances: &[(reqwest::Url, Option<String>)]) -> color_eyre::Result<()> {
+	let started = Instant::now();
+	let settings = client_settings(opts, None);
+	let client = build_client(&settings)?;
+	// Only scrapes and their errors are summarized, a single sample gives no rates
+	let mut stat = RspamdStat::new(opts.num_elements, opts.reset);
+	let mut res = Ok(());
+
+	for (url, url_password) in instances {
+		let password = opts.password.clone().or_else(|| url_password.clone());
+		let settings = ClientSettings { password, ..settings.clone() };
+		let fetched = match fetch_stat(&client, url, &settings).await {
+			Ok(fetched) => {
+				fetched.map_err(|e| eyre!("cannot poll {}: {}", instance_label(url, opts.anonymize_instance), e))
+			},
+			Err(e) => Err(e),
+		};
+		stat.record_scrape(fetched.is_ok());
+
+		match fetched {
+			Ok(json) => {
+				if instances.len() > 1 {
+					println!("# {}", instance_label(url, opts.anonymize_instance));
+				}
+				println!("{}", raw_summary(&json));
+			},
+			Err(e) => {
+				res = Err(e);
+				break;
+			},
+		}
+	}
+
+	if opts.exit_summary {
+		println!("{}", serde_json::to_string(&stat.session_summary(started.elapsed()))?);
+	}
+
+	res
+}
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
 	color_eyre::install()?;
 
 	let opts = CliOpts::parse();
+	opts.validate().map_err(|e| eyre!(e))?;
 
-	let log_level = match opts.verbose {
-		0 => LevelFilter::Warn,
-		1 => LevelFilter::Info,
-		2 => LevelFilter::Debug,
-		_ => LevelFilter::Trace,
-	};
+	logger(opts.verbose, opts.log_file.as_deref())?.try_init()?;
 
-	env_logger::Builder::from_default_env()
-		.filter(None, log_level)
-		.format_timestamp(Some(env_logger::fmt::TimestampPrecision::Micros))
-		.try_init()?;
+	for warning in opts.timeout_warnings() {
+		warn!("{}", warning);
+	}
 
-	let stats = Arc::new(Mutex::new(RspamdStat::new(opts.num_elements)));
+	// Credentials are sent in a header, so the URL is safe to log from now on
+	let instances = opts
+		.url
+		.iter()
+		.map(|url| split_credentials(url))
+		.collect::<color_eyre::Result<Vec<_>>>()?;
+	let instances = if opts.reset {
+		instances
+			.into_iter()
+			.map(|(url, password)| Ok((statreset_url(&url)?, password)))
+			.collect::<color_eyre::Result<Vec<_>>>()?
+	} else {
+		instances
+	};
+	if instances.len() > 1 && opts.fallback_url.is_some() {
+		return Err(eyre!("--fallback-url cannot be used when polling several instances"));
+	}
+	let mode = match (opts.once, opts.mode.clone()) {
+		(false, Some(mode)) => mode,
+		_ => {
+			// Monitoring plugins are judged by the exit code and the status line
+			let res = poll_once(&opts, &instances).await;
+			println!("{}", status_line(&res));
+			std::process::exit(ExitStatus::of(&res).to_code());
+		},
+	};
+	let (url, url_password) = instances[0].clone();
+	let password = opts.password.clone().or(url_password);
+	let fallback = opts.fallback_url.as_deref().map(split_credentials).transpose()?;
+	let fallback = match fallback {
+		Some((fallback_url, fallback_password)) if opts.reset => {
+			Some((statreset_url(&fallback_url)?, fallback_password))
+		},
+		fallback => fallback,
+	};
+	let mut stat = RspamdStat::new(opts.num_elements, opts.reset);
+	stat.spam_includes_soft_reject = opts.spam_includes_soft_reject;
+	stat.total_source = opts.total_source;
+	stat.since_restart = opts.since_restart;
+	stat.health_weights = HealthWeights { latency_limit: opts.health_latency_limit, ..opts.health_weights };
+	stat.action_map = ActionMap::with_overrides(&opts.action_map);
+	stat.selection = opts.counters.clone();
+	stat.export_action_rates = matches!(mode, CliMode::Prometheus(ref prom_opts) if prom_opts.action_rates);
+	// Only the poller updates `stat`, everyone else reads snapshots it publishes
+	let stats = SharedStat::new(stat.clone());
+	let marker_requested = Arc::new(AtomicBool::new(false));
+
+	// Bind before dropping privileges, so privileged ports could be used
+	if let CliMode::Prometheus(ref prom_opts) = mode {
+		let (addr, server) = serve(SocketAddr::from(([0, 0, 0, 0], prom_opts.port)), stats.clone())?;
+		info!("serving metrics on http://{}/metrics", addr);
+		tokio::spawn(async move {
+			if let Err(e) = server.await {
+				error!("metrics endpoint has failed: {}", e);
+			}
+		});
+	}
+	let pushgateway = match mode {
+		CliMode::Pushgateway(ref push_opts) => {
+			let gateway = Pushgateway::new(&push_opts.urls, &push_opts.job, &push_opts.grouping)?;
+			let pusher = tokio::spawn(gateway.clone().run(stats.clone(), push_opts.snapshot_interval));
+			Some((gateway, pusher))
+		},
+		_ => None,
+	};
+	let mut csv_writer = match mode {
+		CliMode::Csv(ref csv_opts) => {
+			Some(CsvWriter::open(csv_opts.output.as_deref(), &stat, csv_opts.csv_rotate, csv_opts.csv_flush_interval)?)
+		},
+		_ => None,
+	};
+	let mut json_writer = matches!(mode, CliMode::Json).then(|| JsonWriter::new(std::io::stdout()));
+	let statsd = match mode {
+		CliMode::Statsd(ref statsd_opts) => {
+			Some(StatsdSender::connect(&statsd_opts.host, statsd_opts.port, &statsd_opts.prefix).await?)
+		},
+		_ => None,
+	};
+	// Opened before dropping privileges, as the dump may be out of reach after chroot
+	let samples = opts.replay.as_deref().map(SampleReader::open).transpose()?;
 	drop_privs(&opts.privdrop);
 
-	tokio::spawn(async move {
-		let stats = stats.clone();
-		let mut niter = 0;
+	let mut chart_height = 0;
+	let (resize_tx, mut resized) = mpsc::unbounded_channel();
+	// Unless summed by --aggregate, charts of each polled instance are shown one below another
+	let stacked = matches!(mode, CliMode::Plot(ref plot_opts) if !plot_opts.aggregate)
+		&& instances.len() > 1
+		&& opts.replay.is_none();
+	let plot_instances = if stacked { instances.len() } else { 1 };
+	if let CliMode::Plot(ref plot_opts) = mode {
+		read_markers(marker_requested.clone());
+		watch_resize(resize_tx);
+		let charts = stat.selected_elements().len() * plot_instances;
+		chart_height = fit_plot_height(plot_opts, charts);
+		enter_term();
+	}
+	let plot_mode = matches!(mode, CliMode::Plot(_));
+
+	let session_started = Instant::now();
+	let exit_summary = opts.exit_summary.then(|| stats.clone());
+
+	let poller = tokio::spawn(async move {
+		let mut stat = stat;
+
+		if let Some(samples) = samples {
+			let mut replayer = Replayer::new(samples, opts.replay_speed);
+			let mut replayed = 0_u64;
+			while let Some(timestamp) = replayer.next(&mut stat).await {
+				// Rows are stamped with the recorded time, so a replayed dump could be used for backfilling
+				let timestamp = DateTime::from_timestamp_millis((timestamp? * 1000.0) as i64)
+					.map(|time| time.with_timezone(&Local).to_rfc3339())
+					.unwrap_or_default();
+				stats.publish(stat.clone());
+
+				if let CliMode::Plot(ref plot_opts) = mode {
+					if replayed > 0 && should_render(replayed - 1, plot_opts.render_every) {
+						stat.display_plot(&plot_settings(plot_opts, chart_height, 0));
+					}
+				}
+				if let Some(writer) = csv_writer.as_mut() {
+					writer.write_row(&stat, &timestamp)?;
+				}
+				if let Some(writer) = json_writer.as_mut() {
+					writer.write_object(&stat, &timestamp)?;
+				}
+				if let Some(sender) = statsd.as_ref() {
+					if let Err(e) = sender.send(&stat).await {
+						debug!("cannot send StatsD metrics: {}", e);
+					}
+				}
+				replayed += 1;
+			}
+
+			info!("replayed {} snapshots", replayed);
+			// The final state stays on the screen and at the metrics endpoint until interrupted
+			if matches!(mode, CliMode::Plot(_) | CliMode::Prometheus(_)) {
+				std::future::pending::<()>().await;
+			}
+			return Ok(());
+		}
+
+		let mut niter = 0_u64;
+		let mut page = 0;
+		let mut page_started = Instant::now();
 		let mut error_counter = 0;
-		let mut elapsed = Duration::from_secs_f32(opts.timeout);
+		let mut backoff = Backoff::default();
+		let mut inconsistent = false;
+		let mut error_throttle = ErrorThrottle::new(opts.error_log_interval);
+		let clock = SystemClock;
+		let mut sample_clock = SampleClock::default();
+		let mut failover = Failover::new(fallback.is_some(), opts.failback_probe);
+		// Start of the previous tick and the pause scheduled after it, with backoff and slow start applied
+		let mut last_tick: Option<(Instant, Duration)> = None;
+		let client_settings = client_settings(&opts, password.clone());
+		// Built once, so the connection pool and keep-alive connections survive between polls
+		let client = build_client(&client_settings)?;
+		// Several instances are polled together and their stats are summed
+		let instance_targets = match instances.len() {
+			1 => Vec::new(),
+			_ => instances
+				.iter()
+				.map(|(instance_url, instance_password)| {
+					let password = opts.password.clone().or_else(|| instance_password.clone());
+					(instance_url.clone(), ClientSettings { password, ..client_settings.clone() })
+				})
+				.collect::<Vec<_>>(),
+		};
+		let mut instances_up = Vec::new();
+		// Each instance keeps its own window, so one of them going down does not drop the sums
+		let mut instance_stats = vec![stat.clone(); instance_targets.len()];
+		let mut instance_clocks = vec![SampleClock::default(); instance_targets.len()];
+		let instance_labels = instance_targets
+			.iter()
+			.map(|(target, _)| instance_label(target, opts.anonymize_instance))
+			.collect::<Vec<_>>();
+		let label = instance_label(&url, opts.anonymize_instance);
+
+		if let Some(wait) = opts.wait_for_ready {
+			if instance_targets.is_empty() {
+				wait_for_ready(&client, &url, &client_settings, wait).await?;
+			}
+			for (target, settings) in &instance_targets {
+				wait_for_ready(&client, target, settings, wait).await?;
+			}
+		}
+
 		loop {
-			let timeout = Duration::from_secs_f32(opts.timeout);
-			let client = reqwest::Client::builder().timeout(timeout).user_agent("rspamd-mon").build()?;
-			let req = client.get(opts.url.as_str()).send();
-			let resp = match req.await {
-				Ok(o) => o.bytes(),
-				Err(e) => {
-					// We should be able to send request
-					return Err(eyre!("cannot get send request to {}: {}", opts.url.as_str(), e));
-				},
+			let poll_interval = opts.poll_interval();
+			let interval = match &opts.schedule {
+				Some(schedule) => schedule.interval_at(Local::now().time(), poll_interval),
+				None => poll_interval,
+			};
+			let interval = match opts.slow_start {
+				Some(warmup) => slow_start_interval(interval, warmup, session_started.elapsed()),
+				None => interval,
 			};
-			let res = match resp.await {
-				Ok(o) => {
-					let json: serde_json::Value = serde_json::from_slice(&o)
-						.map_err(|e| eyre!("malformed json from {}: {}", opts.url.as_str(), e))?;
-					let mut stats_unlocked = stats.lock().await;
-					stats_unlocked
-						.update_from_json(json, elapsed)
-						.map_err(|e| eyre!("cannot get results from {}: {}", opts.url.as_str(), e))?;
+			let now = Instant::now();
+			if let Some((prev, scheduled)) = last_tick {
+				trace!("{}", IntervalDrift::new(prev, now, scheduled));
+			}
+			if marker_requested.swap(false, Ordering::Relaxed) {
+				stat.add_marker();
+				debug!("added event marker");
+			}
+			// Fatal errors are returned by `?`, transient ones are returned in the inner result
+			let tick = async {
+				if !instance_targets.is_empty() {
+					let jsons = match fetch_instances(&client, &instance_targets).await? {
+						Ok(jsons) => jsons,
+						Err(e) => return Ok(Err(e)),
+					};
+					let up = jsons.iter().map(Option::is_some).collect::<Vec<_>>();
+					if up != instances_up {
+						info!("{} of {} instances are up", up.iter().filter(|up| **up).count(), up.len());
+						instances_up = up;
+					}
 
-					Ok(())
-				},
-				Err(e) => Err(eyre!("cannot get results from {}: {}", opts.url.as_str(), e)),
+					let now = clock.now();
+					let mut samples = Vec::with_capacity(jsons.len());
+					let mut first_err = None;
+					for (((json, instance), clock), target_label) in jsons
+						.into_iter()
+						.zip(&mut instance_stats)
+						.zip(&mut instance_clocks)
+						.zip(&instance_labels)
+					{
+						let sample = match json.zip(clock.elapsed(now, poll_interval)) {
+							Some((json, elapsed)) => match instance.ingest(&json, elapsed) {
+								Ok(()) => {
+									clock.record(now);
+									Some(json)
+								},
+								Err(e) => {
+									debug!("cannot get results from {}: {}", target_label, e);
+									first_err.get_or_insert_with(|| {
+										eyre!("cannot get results from {}: {}", target_label, e)
+									});
+									None
+								},
+							},
+							None => None,
+						};
+						samples.push(sample);
+					}
+					stat.aggregate(&instance_stats, &samples);
+
+					return Ok(match first_err {
+						Some(e) if samples.iter().all(Option::is_none) => Err(e),
+						_ => Ok(()),
+					});
+				}
+
+				let fetched = {
+					let mut fetched = Ok(Err(eyre!("no endpoints to poll")));
+					for endpoint in failover.order() {
+						let (target, target_password) = match (endpoint, &fallback) {
+							(Endpoint::Primary, _) => (&url, &password),
+							(Endpoint::Fallback, Some((fallback_url, fallback_password))) => {
+								(fallback_url, if fallback_password.is_some() { fallback_password } else { &password })
+							},
+							(Endpoint::Fallback, None) => continue,
+						};
+						let settings = ClientSettings { password: target_password.clone(), ..client_settings.clone() };
+						fetched = fetch_stat(&client, target, &settings).await;
+
+						match &fetched {
+							Ok(Ok(_)) => {
+								if failover.report_success(endpoint) {
+									info!(
+										"switched to {:?} endpoint {}",
+										endpoint,
+										instance_label(target, opts.anonymize_instance)
+									);
+									// Counters of another instance cannot be compared with the previous ones
+									stat.reset_counters();
+								}
+								break;
+							},
+							Ok(Err(e)) | Err(e) => debug!("{:?} endpoint has failed: {}", endpoint, e),
+						}
+					}
+
+					fetched
+				};
+
+				let res = match fetched? {
+					Ok(json) => {
+						let now = clock.now();
+						match sample_clock.elapsed(now, poll_interval) {
+							Some(elapsed) => {
+								// A document that cannot be ingested is transient, like in the multi-instance path
+								if let Err(e) = stat.update_from_json(json, elapsed) {
+									return Ok(Err(eyre!("cannot get results from {}: {}", label, e)));
+								}
+								sample_clock.record(now);
+							},
+							None => debug!("no time elapsed since the previous sample, skipping tick"),
+						}
+
+						Ok(())
+					},
+					Err(e) => Err(e),
+				};
+
+				Ok::<_, color_eyre::Report>(res)
 			};
+			let res = run_tick(opts.tick_deadline, tick).await?;
+
+			stat.record_scrape(res.is_ok());
+			stat.poll_interval = Some(interval);
+			stats.publish(stat.clone());
 
 			let _ = if let Err(e) = res {
 				error_counter += 1;
-				elapsed = elapsed + elapsed;
+				backoff.failure();
+				if let Some(line) = error_throttle.check(&e.to_string(), Instant::now()) {
+					warn!("{}", line);
+				}
 
-				if error_counter > MAX_NET_ERRORS {
+				if opts.max_errors.is_some_and(|max| error_counter > max) {
 					Err(e)
 				} else {
 					Ok(())
 				}
 			} else {
 				error_counter = 0;
-				elapsed = timeout;
-
-				if let CliMode::Plot(ref plot_opts) = opts.mode {
-					let stats_unlocked = stats.lock().await;
-					if niter > 0 {
-						stats_unlocked.display_plot(plot_opts.chart_height as u16);
-					} else {
-						info!("connected to {}, waiting for data", opts.url.as_str());
+				backoff.success();
+				error_throttle.reset();
+
+				// Reported once, not on every poll
+				match consistency_check(&stat) {
+					Some(warning) if !inconsistent => {
+						warn!("{}", warning);
+						inconsistent = true;
+					},
+					None if inconsistent => {
+						info!("scanned and action counters are consistent again");
+						inconsistent = false;
+					},
+					_ => {},
+				}
+
+				if let CliMode::Plot(ref plot_opts) = mode {
+					if niter > 0 && should_render(niter - 1, plot_opts.render_every) {
+						if page_started.elapsed() >= plot_opts.page_interval {
+							page += 1;
+							page_started = Instant::now();
+						}
+						let settings = plot_settings(plot_opts, chart_height, page);
+						if stacked {
+							display_stacked_plot(&mut instance_stats, &instance_labels, &settings);
+						} else {
+							stat.display_plot(&settings);
+						}
+					} else if niter == 0 {
+						info!("connected to {}, waiting for data", label);
 					}
 					niter += 1;
 				}
 
+				if let Some(writer) = csv_writer.as_mut() {
+					writer.write_row(&stat, &clock.wall().to_rfc3339())?;
+				}
+				if let Some(writer) = json_writer.as_mut() {
+					writer.write_object(&stat, &clock.wall().to_rfc3339())?;
+				}
+
+				// Shipping metrics is best effort, the monitor keeps polling anyway
+				if let Some(sender) = statsd.as_ref() {
+					if let Err(e) = sender.send(&stat).await {
+						debug!("cannot send StatsD metrics: {}", e);
+					}
+				}
+
 				Ok(())
 			}?;
 
-			tokio::time::sleep(timeout).await;
+			let scheduled = backoff.interval(interval);
+			last_tick = Some((now, scheduled));
+			// Unlike adding the pause to an instant, `sleep` does not overflow on huge intervals
+			let next_poll = tokio::time::sleep(scheduled);
+			tokio::pin!(next_poll);
+			loop {
+				tokio::select! {
+					_ = &mut next_poll => break,
+					Some(()) = resized.recv() => {
+						if let CliMode::Plot(ref plot_opts) = mode {
+							chart_height = fit_plot_height(plot_opts, stat.selected_elements().len() * plot_instances);
+							if niter > 1 {
+								let settings = plot_settings(plot_opts, chart_height, page);
+								if stacked {
+									display_stacked_plot(&mut instance_stats, &instance_labels, &settings);
+								} else {
+									stat.display_plot(&settings);
+								}
+							}
+						}
+					},
+				}
+			}
 		}
-	})
-	.await?
+	});
+
+	// A panicked poller still has to give the terminal back and print the summary
+	let res = tokio::select! {
+		res = poller => res.map_err(Into::into).and_then(|res| res),
+		Ok(()) = tokio::signal::ctrl_c() => {
+			info!("interrupted, shutting down");
+			Ok(())
+		},
+	};
+	if plot_mode {
+		restore_term();
+	}
+
+	if let Some(stats) = exit_summary {
+		let summary = stats.load().session_summary(session_started.elapsed());
+		println!("{}", serde_json::to_string(&summary)?);
+	}
+
+	// On a clean shutdown the group is deleted, so the gateways do not keep serving the last metrics forever
+	if let Some((gateway, pusher)) = pushgateway {
+		pusher.abort();
+		if res.is_ok() {
+			if let Err(e) = gateway.delete().await {
+				warn!("{}", e);
+			}
+		}
+	}
+
+	res
 }
@@ -0,0 +1,602 @@
+use crate::{
+	client::TlsMinVersion,
+	counters::{parse_num_elements, ActionMapping, CounterSelection, KnownCounter, TotalSource},
+	csv::CsvRotation,
+	health::HealthWeights,
+	plot::{CaptionFields, ColorChoice},
+	poll::{parse_duration, parse_interval, Schedule},
+};
+use clap::Parser;
+use std::{path::PathBuf, time::Duration};
+
+#[derive(Clone, Debug, Parser, Default)]
+#[clap(rename_all = "kebab-case")]
+pub struct PlotOptions {
+	/// Chart height.
+	#[clap(long, default_value = "6")]
+	pub chart_height: usize,
+	/// Use logarithmic y-scaling for charts (captions still show real values).
+	#[clap(long)]
+	pub log_scale: bool,
+	/// Draw only this counter using the whole terminal: spam, ham, junk, total, avg_time, scan_p50, scan_p95,
+	/// scan_p99, scanned, learned, connections, ham_percentage, bytes_allocated or chunks_oversized.
+	#[clap(long, parse(try_from_str = parse_counter))]
+	pub focus: Option<KnownCounter>,
+	/// Show acceptance and rejection rates over the whole window below the charts.
+	#[clap(long)]
+	pub window_summary: bool,
+	/// Round plotted values to this number of decimals (captions keep full precision).
+	#[clap(long)]
+	pub round: Option<u32>,
+	/// Draw chart lines using a per-counter color.
+	#[clap(long)]
+	pub line_colors: bool,
+	/// Show at most this number of charts at once, rotating pages.
+	#[clap(long)]
+	pub charts_per_page: Option<usize>,
+	/// How often to switch to the next page of charts, e.g. `10s` (plain numbers are seconds).
+	#[clap(long, default_value = "5.0", parse(try_from_str = parse_interval))]
+	pub page_interval: Duration,
+	/// Redraw charts only every N polls (data is still collected on each poll).
+	#[clap(long, default_value = "1")]
+	pub render_every: u32,
+	/// Show composite health score (0-100) below the charts.
+	#[clap(long)]
+	pub health: bool,
+	/// Show correlation of two counters below the charts, e.g. `total,avg_time`.
+	#[clap(long, parse(try_from_str = parse_counter_pair))]
+	pub correlate: Option<(KnownCounter, KnownCounter)>,
+	/// Extra fields shown in chart captions, comma separated: sd (standard deviation), delta (raw count change in
+	/// the last tick), since_start (raw count since the start, see --since-restart).
+	#[clap(long, default_value = "")]
+	pub caption_fields: CaptionFields,
+	/// Plot total as the running mean over the window (captions keep per-tick values).
+	#[clap(long)]
+	pub smooth_total: bool,
+	/// Plot the moving average over this number of values (captions and exported metrics keep raw values).
+	#[clap(long)]
+	pub smooth: Option<usize>,
+	/// Show short single-line captions (chosen automatically on narrow terminals).
+	#[clap(long)]
+	pub compact_caption: bool,
+	/// Draw a reference line at this scan time (in seconds) on the avg_time chart, e.g. a latency SLO.
+	#[clap(long)]
+	pub slo_scan_time: Option<f64>,
+	/// Use colors: auto (only on a terminal and unless NO_COLOR is set), always or never.
+	#[clap(long, default_value = "auto")]
+	pub color: ColorChoice,
+	/// Sum rates of all instances given by several `--url` into one set of charts instead of stacking charts of
+	/// each instance.
+	#[clap(long)]
+	pub aggregate: bool,
+}
+
+fn parse_counter(name: &str) -> Result<KnownCounter, String> {
+	KnownCounter::from_name(name).ok_or_else(|| format!("unknown counter: {}", name))
+}
+
+fn parse_counter_pair(s: &str) -> Result<(KnownCounter, KnownCounter), String> {
+	let (a, b) = s
+		.split_once(',')
+		.ok_or_else(|| format!("expected two counters separated by comma: {}", s))?;
+	Ok((parse_counter(a.trim())?, parse_counter(b.trim())?))
+}
+
+fn parse_grouping_label(s: &str) -> Result<(String, String), String> {
+	let (name, value) = s.split_once('=').ok_or_else(|| format!("expected name=value: {}", s))?;
+	let name = name.trim();
+	if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+		return Err(format!("invalid label name: {}", name));
+	}
+	// Such values need base64 encoding in the group URL
+	if value.is_empty() || value.contains('/') {
+		return Err(format!("label value cannot be empty or contain slashes: {}", s));
+	}
+
+	Ok((name.to_owned(), value.to_owned()))
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+	s.trim()
+		.parse::<f64>()
+		.ok()
+		.filter(|speed| speed.is_finite() && *speed > 0.0)
+		.ok_or_else(|| format!("invalid speed: {}, expected a positive number", s))
+}
+
+#[derive(Clone, Debug, Parser, Default)]
+#[clap(rename_all = "kebab-case")]
+pub struct PrometheusOptions {
+	/// Prometheus endpoint port.
+	#[clap(long, default_value = "65432")]
+	pub port: u16,
+	/// Also export the rate of each action, as `rspamd_mon_action_rate{action="greylist"}`.
+	#[clap(long)]
+	pub action_rates: bool,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[clap(rename_all = "kebab-case")]
+pub struct PushgatewayOptions {
+	/// Pushgateway URL, e.g. `http://pushgateway:9091`; repeat to push to several gateways.
+	#[clap(long = "pushgateway-url", required = true)]
+	pub urls: Vec<reqwest::Url>,
+	/// Job label of the pushed metrics group.
+	#[clap(long, default_value = "rspamd-mon")]
+	pub job: String,
+	/// Extra label of the metrics group, e.g. `instance=mx1`; may be repeated.
+	#[clap(long, parse(try_from_str = parse_grouping_label))]
+	pub grouping: Vec<(String, String)>,
+	/// How often to push the latest metrics, e.g. `15s` (plain numbers are seconds).
+	#[clap(long, default_value = "15s", parse(try_from_str = parse_interval))]
+	pub snapshot_interval: Duration,
+}
+
+#[derive(Clone, Debug, Parser, Default)]
+#[clap(rename_all = "kebab-case")]
+pub struct CsvOptions {
+	/// Append rows to this file instead of stdout.
+	#[clap(long)]
+	pub output: Option<PathBuf>,
+	/// Start a new output file: none, daily or size:<bytes>; the full file is renamed after the time of its first
+	/// row, e.g. `stats-20220301T100000.csv`.
+	#[clap(long, default_value = "none", requires = "output")]
+	pub csv_rotate: CsvRotation,
+	/// Batch rows, flushing them at most this often, e.g. `30s`; every row is flushed at once by default.
+	#[clap(long, parse(try_from_str = parse_interval))]
+	pub csv_flush_interval: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[clap(rename_all = "kebab-case")]
+pub struct StatsdOptions {
+	/// StatsD server host, or `unix:/path/to/socket` for a local collector reading a Unix datagram socket.
+	#[clap(long, default_value = "localhost")]
+	pub host: String,
+	/// StatsD server port.
+	#[clap(long, default_value = "8125")]
+	pub port: u16,
+	/// Metric namespace, e.g. `rspamd` gives `rspamd.spam`; may be empty.
+	#[clap(long, default_value = "rspamd")]
+	pub prefix: String,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[clap(rename_all = "kebab-case")]
+pub enum CliMode {
+	/// CLI chart mode.
+	Plot(PlotOptions),
+	/// Prometheus endpoint mode, also serving a dashboard at `/`, counter history at `/history` and averages over
+	/// a window at `/rate`; `POST /admin/pause` toggles exporting.
+	Prometheus(PrometheusOptions),
+	/// Push metrics to Prometheus Pushgateways, for runs too short to be scraped; the group is deleted on exit.
+	Pushgateway(PushgatewayOptions),
+	/// CSV output mode.
+	Csv(CsvOptions),
+	/// StatsD/Graphite gauges over UDP or a Unix datagram socket.
+	Statsd(StatsdOptions),
+	/// Newline-delimited JSON objects on stdout.
+	Json,
+}
+
+#[derive(Debug, Parser)]
+pub struct CliOpts {
+	/// Rspamd stat URL, the password may be passed as `http://:password@host:11334/stat`, Unix sockets are
+	/// specified as `unix:/var/run/rspamd/controller.sock:/stat`; repeat to poll several instances and sum their
+	/// stats
+	#[clap(name = "url", long, default_value = "http://localhost:11334/stat")]
+	pub url: Vec<String>,
+	/// Rspamd controller password, takes precedence over the one in the URL
+	#[clap(long, env = "RSPAMD_PASSWORD", hide_env_values = true)]
+	pub password: Option<String>,
+	/// Poll Rspamd through this SOCKS5 proxy (`host:port`), e.g. an SSH tunnel
+	#[clap(long)]
+	pub socks5: Option<String>,
+	/// Count `soft reject` actions as spam
+	#[clap(long)]
+	pub spam_includes_soft_reject: bool,
+	/// Override the chart an action is counted in, e.g. `--map greylist=spam`; categories are spam, ham,
+	/// junk and ignore
+	#[clap(long = "map")]
+	pub action_map: Vec<ActionMapping>,
+	/// Show and export only these counters, comma separated, e.g. `spam,ham,total`
+	#[clap(long)]
+	pub counters: Option<CounterSelection>,
+	/// What drives the total chart: action-sum (spam, ham and junk) or scanned (all messages)
+	#[clap(long, default_value = "action-sum")]
+	pub total_source: TotalSource,
+	/// Start the since-start totals over when Rspamd is restarted (its uptime drops or its counters decrease)
+	#[clap(long)]
+	pub since_restart: bool,
+	/// Show a stable hash instead of the host name of an instance in the output and logs
+	#[clap(long)]
+	pub anonymize_instance: bool,
+	/// Poll `/statreset` instead of `/stat`, so Rspamd zeroes its counters after each read
+	#[clap(long)]
+	pub reset: bool,
+	/// Standby URL polled while the primary one is failing
+	#[clap(long)]
+	pub fallback_url: Option<String>,
+	/// While on the standby URL, try the primary one every N polls
+	#[clap(long, default_value = "10")]
+	pub failback_probe: u32,
+	/// Verbosity level: -v - info, -vv - debug, -vvv - trace
+	#[clap(short = 'v', long, parse(from_occurrences))]
+	pub verbose: i8,
+	/// Write logs to this file instead of stderr, so they do not mix with charts on a terminal
+	#[clap(long)]
+	pub log_file: Option<PathBuf>,
+	/// How often do we poll Rspamd, e.g. `500ms`, `2s` or `1m` (plain numbers are seconds)
+	#[clap(long, default_value = "1.0", parse(try_from_str = parse_interval))]
+	pub interval: Duration,
+	/// Deprecated alias of --interval
+	#[clap(long, hide = true, conflicts_with = "interval", parse(try_from_str = parse_interval))]
+	pub timeout: Option<Duration>,
+	/// Poll less often right after startup, shrinking to the interval over this warmup, e.g. `1m`
+	#[clap(long, parse(try_from_str = parse_duration))]
+	pub slow_start: Option<Duration>,
+	/// HTTP request timeout, e.g. `5s`; the poll interval by default
+	#[clap(long, parse(try_from_str = parse_interval))]
+	pub request_timeout: Option<Duration>,
+	/// Give up connecting to Rspamd after this long, e.g. `200ms`; requests are still limited by --request-timeout
+	#[clap(long, parse(try_from_str = parse_interval))]
+	pub connect_timeout: Option<Duration>,
+	/// Elements to store (and display), at least 2
+	#[clap(long, default_value = "80", parse(try_from_str = parse_num_elements))]
+	pub num_elements: usize,
+	/// Print a JSON summary of the session to stdout on exit
+	#[clap(long)]
+	pub exit_summary: bool,
+	/// Health score weights of spam ratio, scan time and scrape errors
+	#[clap(long, global = true, default_value = "0.4,0.4,0.2")]
+	pub health_weights: HealthWeights,
+	/// Scan time (in seconds) considered as bad as it gets for the health score
+	#[clap(long, global = true, default_value = "2.0")]
+	pub health_latency_limit: f64,
+	/// Exit after this many consecutive failed polls; by default polling backs off and goes on through outages
+	#[clap(long)]
+	pub max_errors: Option<u32>,
+	/// Log a repeated scrape error at most once per this interval, e.g. `1m`
+	#[clap(long, default_value = "60s", parse(try_from_str = parse_duration))]
+	pub error_log_interval: Duration,
+	/// On startup, wait this long for Rspamd to come up before polling, e.g. `30s`
+	#[clap(long, parse(try_from_str = parse_duration))]
+	pub wait_for_ready: Option<Duration>,
+	/// Retry a failed request up to this number of times within the same poll
+	#[clap(long, default_value = "0")]
+	pub tick_retries: u32,
+	/// Abort a poll (request, parsing and update) that takes longer than this, e.g. `3s` (plain numbers are seconds)
+	#[clap(long, parse(try_from_str = parse_interval))]
+	pub tick_deadline: Option<Duration>,
+	/// Poll intervals for time ranges of the day, e.g. `22:00-06:00=10s,12:00-13:00=500ms` (plain numbers are seconds)
+	#[clap(long)]
+	pub schedule: Option<Schedule>,
+	/// Minimal TLS version for HTTPS connections: 1.2 or 1.3
+	#[clap(long)]
+	pub tls_min_version: Option<TlsMinVersion>,
+	/// Override the Host header, e.g. to reach a specific virtual host
+	#[clap(long)]
+	pub host_header: Option<String>,
+	/// PEM client certificate for controllers behind mutual TLS (https URLs only)
+	#[clap(long)]
+	pub client_cert: Option<PathBuf>,
+	/// PEM private key of the client certificate, unless it is in the certificate file
+	#[clap(long, requires = "client-cert")]
+	pub client_key: Option<PathBuf>,
+	/// PEM certificate of a private CA to trust (https URLs only)
+	#[clap(long)]
+	pub ca_cert: Option<PathBuf>,
+	/// Do not verify server certificates, e.g. self-signed ones (https URLs only)
+	#[clap(long)]
+	pub insecure: bool,
+	/// Poll once, print raw cumulative counters and uptime and exit; no mode is needed. Ends with a status line and
+	/// a Nagios style exit code: 0 (OK), 2 (CRITICAL, wrong password) or 3 (UNKNOWN, Rspamd cannot be polled)
+	#[clap(long)]
+	pub once: bool,
+	/// Feed snapshots from a newline-delimited JSON dump (gzip'd if ending with `.gz`) instead of polling Rspamd;
+	/// each snapshot needs a `timestamp`, Unix time or RFC 3339
+	#[clap(long, alias = "sample-file", conflicts_with = "once")]
+	pub replay: Option<PathBuf>,
+	/// Replay this many times faster than the snapshots were recorded; as fast as possible by default
+	#[clap(long, requires = "replay", parse(try_from_str = parse_speed))]
+	pub replay_speed: Option<f64>,
+	#[clap(flatten)]
+	#[cfg(all(unix, feature = "drop_privs"))]
+	pub privdrop: PrivDropConfig,
+	#[clap(subcommand)]
+	pub mode: Option<CliMode>,
+}
+
+/// Request timeouts this many times longer than the poll interval are reported as likely mistakes
+const MAX_REQUEST_TIMEOUT_RATIO: u32 = 10;
+
+impl CliOpts {
+	/// Time between polls, `--timeout` is its deprecated alias
+	pub fn poll_interval(&self) -> Duration {
+		self.timeout.unwrap_or(self.interval)
+	}
+
+	/// HTTP request timeout, the poll interval unless it is set explicitly
+	pub fn request_timeout(&self) -> Duration {
+		self.request_timeout.unwrap_or_else(|| self.poll_interval())
+	}
+
+	/// Returns warnings about deprecated or suspicious timeout options
+	pub fn timeout_warnings(&self) -> Vec<String> {
+		let mut warnings = Vec::new();
+
+		if self.timeout.is_some() {
+			warnings.push("--timeout is deprecated, use --interval instead".to_owned());
+		}
+		let max_timeout = self.poll_interval().checked_mul(MAX_REQUEST_TIMEOUT_RATIO);
+		if max_timeout.is_some_and(|max_timeout| self.request_timeout() > max_timeout) {
+			warnings.push(format!(
+				"request timeout of {:?} is much longer than the poll interval of {:?}, a hanging request delays \
+				 polls that much",
+				self.request_timeout(),
+				self.poll_interval()
+			));
+		}
+
+		warnings
+	}
+
+	/// Checks what clap cannot express: a mode is required unless polling once
+	pub fn validate(&self) -> Result<(), String> {
+		match (&self.mode, self.once) {
+			(None, false) => Err("a mode is required unless --once is given".to_owned()),
+			_ => Ok(()),
+		}
+	}
+}
+
+#[cfg(all(unix, feature = "drop_privs"))]
+#[derive(Debug, Parser, Clone, Default)]
+#[clap(rename_all = "kebab-case")]
+pub struct PrivDropConfig {
+	/// Run as this user and their primary group
+	#[clap(short = 'u', long)]
+	pub user: Option<String>,
+	/// Run as this group
+	#[clap(short = 'g', long)]
+	pub group: Option<String>,
+	/// Chroot to this directory
+	#[clap(long)]
+	pub chroot: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		cli::{CliMode, CliOpts},
+		counters::{CounterSelection, KnownCounter},
+		csv::CsvRotation,
+	};
+	use clap::Parser;
+	use std::time::Duration;
+
+	#[test]
+	fn cli_opts_test() {
+		let opts = CliOpts::try_parse_from([
+			"rspamd-mon",
+			"--url",
+			"http://rspamd:11334/stat",
+			"--num-elements",
+			"10",
+			"plot",
+			"--chart-height",
+			"4",
+			"--focus",
+			"total",
+		])
+		.unwrap();
+
+		assert_eq!(opts.url, ["http://rspamd:11334/stat"]);
+		assert!(!opts.reset);
+		assert_eq!(opts.num_elements, 10);
+		match opts.mode {
+			Some(CliMode::Plot(plot)) => {
+				assert_eq!(plot.chart_height, 4);
+				assert_eq!(plot.focus, Some(KnownCounter::Total));
+			},
+			_ => panic!("expected plot mode"),
+		}
+
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "plot"]).unwrap();
+		assert_eq!(opts.url, ["http://localhost:11334/stat"]);
+		assert!(CliOpts::try_parse_from(["rspamd-mon"]).unwrap().validate().is_err());
+	}
+
+	#[test]
+	fn modes_test() {
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "prometheus", "--port", "9100"]).unwrap();
+		assert!(matches!(opts.mode, Some(CliMode::Prometheus(ref prom)) if prom.port == 9100 && !prom.action_rates));
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "prometheus", "--action-rates"]).unwrap();
+		assert!(matches!(opts.mode, Some(CliMode::Prometheus(ref prom)) if prom.action_rates));
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "csv", "--output", "stats.csv"]).unwrap();
+		assert!(
+			matches!(opts.mode, Some(CliMode::Csv(ref csv)) if csv.output.as_deref() == Some("stats.csv".as_ref()))
+		);
+		let opts = CliOpts::try_parse_from([
+			"rspamd-mon",
+			"csv",
+			"--output",
+			"stats.csv",
+			"--csv-rotate",
+			"size:1000000",
+			"--csv-flush-interval",
+			"30s",
+		])
+		.unwrap();
+		assert!(matches!(opts.mode, Some(CliMode::Csv(ref csv))
+			if csv.csv_rotate == CsvRotation::Size(1_000_000) && csv.csv_flush_interval == Some(Duration::from_secs(30))));
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "csv", "--csv-rotate", "daily"]).is_err());
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "statsd", "--host", "graphite", "--prefix", "mail"]).unwrap();
+		assert!(
+			matches!(opts.mode, Some(CliMode::Statsd(ref statsd)) if statsd.host == "graphite" && statsd.port == 8125 && statsd.prefix == "mail")
+		);
+		assert!(matches!(CliOpts::try_parse_from(["rspamd-mon", "json"]).unwrap().mode, Some(CliMode::Json)));
+	}
+
+	#[test]
+	fn pushgateway_test() {
+		let opts = CliOpts::try_parse_from([
+			"rspamd-mon",
+			"pushgateway",
+			"--pushgateway-url",
+			"http://gw1:9091",
+			"--pushgateway-url",
+			"http://gw2:9091",
+			"--grouping",
+			"instance=mx1",
+		])
+		.unwrap();
+		let push = match opts.mode {
+			Some(CliMode::Pushgateway(push)) => push,
+			mode => panic!("unexpected mode: {:?}", mode),
+		};
+		assert_eq!(push.urls.len(), 2);
+		assert_eq!(push.job, "rspamd-mon");
+		assert_eq!(push.grouping, [("instance".to_owned(), "mx1".to_owned())]);
+		assert_eq!(push.snapshot_interval, Duration::from_secs(15));
+
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "pushgateway"]).is_err());
+		for grouping in ["instance", "inst-ance=mx1", "path=a/b", "instance="] {
+			let args = ["rspamd-mon", "pushgateway", "--pushgateway-url", "http://gw:9091", "--grouping", grouping];
+			assert!(CliOpts::try_parse_from(args).is_err(), "{}", grouping);
+		}
+	}
+
+	#[test]
+	fn num_elements_test() {
+		assert_eq!(CliOpts::try_parse_from(["rspamd-mon", "plot"]).unwrap().num_elements, 80);
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--num-elements", "2", "plot"]).unwrap();
+		assert_eq!(opts.num_elements, 2);
+		// A rate needs two samples
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--num-elements", "1", "plot"]).is_err());
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--num-elements", "0", "plot"]).is_err());
+	}
+
+	#[test]
+	fn durations_test() {
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--interval", "500ms", "plot"]).unwrap();
+		assert_eq!(opts.poll_interval(), Duration::from_millis(500));
+		assert_eq!(opts.request_timeout(), Duration::from_millis(500));
+		assert!(opts.timeout_warnings().is_empty());
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--interval", "2", "plot"]).unwrap();
+		assert_eq!(opts.poll_interval(), Duration::from_secs(2));
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--interval", "2 fortnights", "plot"]).is_err());
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--interval", "18446744073709551615h", "plot"]).is_err());
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--interval", "0", "plot"]).is_err());
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--request-timeout", "0ms", "plot"]).is_err());
+	}
+
+	#[test]
+	fn timeouts_test() {
+		let opts =
+			CliOpts::try_parse_from(["rspamd-mon", "--interval", "1s", "--request-timeout", "5s", "plot"]).unwrap();
+		assert_eq!(opts.poll_interval(), Duration::from_secs(1));
+		assert_eq!(opts.request_timeout(), Duration::from_secs(5));
+		assert!(opts.timeout_warnings().is_empty());
+		assert_eq!(opts.connect_timeout, None);
+
+		// The deprecated option still sets the interval
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--timeout", "2s", "plot"]).unwrap();
+		assert_eq!(opts.poll_interval(), Duration::from_secs(2));
+		assert_eq!(opts.request_timeout(), Duration::from_secs(2));
+		assert!(opts.timeout_warnings()[0].contains("deprecated"));
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--timeout", "2s", "--interval", "1s", "plot"]).is_err());
+
+		let opts =
+			CliOpts::try_parse_from(["rspamd-mon", "--interval", "1s", "--request-timeout", "1m", "plot"]).unwrap();
+		assert_eq!(opts.timeout_warnings().len(), 1);
+		assert!(opts.timeout_warnings()[0].contains("much longer"));
+
+		// Ten times such an interval does not fit into a Duration
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--interval", "10000000000000000000s", "plot"]).unwrap();
+		assert!(opts.timeout_warnings().is_empty());
+
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--connect-timeout", "200ms", "plot"]).unwrap();
+		assert_eq!(opts.connect_timeout, Some(Duration::from_millis(200)));
+	}
+
+	#[test]
+	fn wait_for_ready_test() {
+		assert_eq!(CliOpts::try_parse_from(["rspamd-mon", "plot"]).unwrap().wait_for_ready, None);
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--wait-for-ready", "30s", "plot"]).unwrap();
+		assert_eq!(opts.wait_for_ready, Some(Duration::from_secs(30)));
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--wait-for-ready", "-1s", "plot"]).is_err());
+	}
+
+	#[test]
+	fn client_cert_test() {
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--client-key", "client.key", "plot"]).is_err());
+		let opts = CliOpts::try_parse_from([
+			"rspamd-mon",
+			"--client-cert",
+			"client.pem",
+			"--client-key",
+			"client.key",
+			"plot",
+		])
+		.unwrap();
+		assert_eq!(opts.client_cert.as_deref(), Some("client.pem".as_ref()));
+		assert_eq!(opts.client_key.as_deref(), Some("client.key".as_ref()));
+	}
+
+	#[test]
+	fn once_test() {
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--once"]).unwrap();
+		assert!(opts.mode.is_none());
+		assert!(opts.validate().is_ok());
+		// A single poll is summarized too
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--once", "--exit-summary"]).unwrap();
+		assert!(opts.exit_summary && opts.validate().is_ok());
+	}
+
+	#[test]
+	fn replay_test() {
+		let opts =
+			CliOpts::try_parse_from(["rspamd-mon", "--sample-file", "dump.ndjson.gz", "--replay-speed", "60", "csv"])
+				.unwrap();
+		assert_eq!(opts.replay.as_deref(), Some("dump.ndjson.gz".as_ref()));
+		assert_eq!(opts.replay_speed, Some(60.0));
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--replay-speed", "2", "csv"]).is_err());
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--replay", "dump", "--replay-speed", "-1", "csv"]).is_err());
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--replay", "dump", "--once"]).is_err());
+	}
+
+	#[test]
+	fn health_options_test() {
+		// Health weights are shared by the plot and the exports, and still accepted after the mode
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "plot", "--health", "--health-weights", "1,0,0"]).unwrap();
+		assert_eq!(opts.health_weights, "1,0,0".parse().unwrap());
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "plot", "--health-weights", "2,-1,0"]).is_err());
+	}
+
+	#[test]
+	fn action_map_test() {
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--map", "greylist=spam", "--map", "add header=ham", "plot"])
+			.unwrap();
+		assert_eq!(opts.action_map.len(), 2);
+		assert_eq!(opts.action_map[1].action, "add header");
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--map", "greylist", "plot"]).is_err());
+	}
+
+	#[test]
+	fn instances_test() {
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--url", "http://a/stat", "--url", "http://b/stat", "plot"])
+			.unwrap();
+		assert_eq!(opts.url, ["http://a/stat", "http://b/stat"]);
+		assert!(matches!(opts.mode, Some(CliMode::Plot(ref plot)) if !plot.aggregate));
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--url", "http://a/stat", "plot", "--aggregate"]).unwrap();
+		assert!(matches!(opts.mode, Some(CliMode::Plot(ref plot)) if plot.aggregate));
+	}
+
+	#[test]
+	fn counters_test() {
+		let opts = CliOpts::try_parse_from(["rspamd-mon", "--counters", "spam,total", "plot"]).unwrap();
+		assert_eq!(opts.counters, Some(CounterSelection(vec![KnownCounter::Spam, KnownCounter::Total])));
+		assert!(CliOpts::try_parse_from(["rspamd-mon", "--counters", "spam,nope", "plot"]).is_err());
+	}
+}
@@ -1,10 +1,22 @@
 use accurate::{sum::Sum2, traits::SumWithAccumulator};
 use color_eyre::eyre::eyre;
+use log::debug;
+use serde_derive::Serialize;
 
-use std::{collections::VecDeque, error::Error, time::Duration};
+use std::{
+	collections::{BTreeMap, VecDeque},
+	error::Error,
+	str::FromStr,
+	sync::Arc,
+	time::Duration,
+};
 
-use crate::plot::*;
+use crate::{
+	health::{health_score, HealthWeights},
+	plot::*,
+};
 
+#[derive(Clone)]
 pub struct CounterData<T> {
 	/// Current counter value
 	cur_value: T,
@@ -23,9 +35,22 @@ pub trait Counter<T> {
 	fn label(&self) -> &'static str;
 	/// Returns a current value of the counter
 	fn cur_value(&self) -> T;
+	/// Forgets the current value, so the next update starts from scratch
+	fn reset(&mut self);
+	/// Copies the counter with its current value
+	fn box_clone(&self) -> Box<dyn Counter<T> + Send + Sync>;
+	/// Returns the raw change of the value on the last update, for counters that track it
+	fn last_delta(&self) -> Option<T> {
+		None
+	}
+	/// Whether the last update found a cumulative value decreased, i.e. Rspamd has been restarted
+	fn restarted(&self) -> bool {
+		false
+	}
 }
 
 /// A counter which is used to represent gauge
+#[derive(Clone)]
 pub struct GaugeCounter(CounterData<f64>);
 
 impl Counter<f64> for GaugeCounter {
@@ -46,19 +71,88 @@ impl Counter<f64> for GaugeCounter {
 	fn cur_value(&self) -> f64 {
 		self.0.cur_value
 	}
+
+	fn reset(&mut self) {
+		self.0.cur_value = f64::NAN;
+	}
+
+	fn box_clone(&self) -> Box<dyn Counter<f64> + Send + Sync> {
+		Box::new(Self(self.0.clone()))
+	}
 }
 
 /// A counter that checks the difference
-pub struct DiffCounter(CounterData<f64>);
+#[derive(Clone)]
+pub struct DiffCounter {
+	data: CounterData<f64>,
+	/// Raw difference of the last update, before dividing by the elapsed time
+	last_delta: f64,
+	restarted: bool,
+}
 
 impl Counter<f64> for DiffCounter {
+	fn update(&mut self, new_value: f64, ms: usize) -> Result<f64, Box<dyn Error + Send + Sync>> {
+		let old_value = self.data.cur_value;
+		// A decreasing cumulative value means Rspamd has been restarted, so the new value is just a new baseline
+		let restarted = new_value < old_value;
+		if restarted {
+			debug!("{} counter reset: {} -> {}", self.data.label, old_value, new_value);
+		}
+		let diff = if old_value.is_nan() || restarted { f64::NAN } else { new_value - old_value };
+		self.data.cur_value = new_value;
+		self.last_delta = diff;
+		self.restarted = restarted;
+		match ms {
+			0 => Err("division by zero".to_owned().into()),
+			_ => Ok(diff / (ms as f64)),
+		}
+	}
+
+	fn new(label: &'static str) -> Self {
+		Self { data: CounterData { cur_value: f64::NAN, label }, last_delta: f64::NAN, restarted: false }
+	}
+
+	fn label(&self) -> &'static str {
+		self.data.label
+	}
+
+	fn cur_value(&self) -> f64 {
+		self.data.cur_value
+	}
+
+	fn reset(&mut self) {
+		self.data.cur_value = f64::NAN;
+		self.last_delta = f64::NAN;
+		self.restarted = false;
+	}
+
+	fn box_clone(&self) -> Box<dyn Counter<f64> + Send + Sync> {
+		Box::new(self.clone())
+	}
+
+	fn last_delta(&self) -> Option<f64> {
+		Some(self.last_delta).filter(|delta| !delta.is_nan())
+	}
+
+	fn restarted(&self) -> bool {
+		self.restarted
+	}
+}
+
+/// A counter of values accumulated over the last interval only, e.g. read from `/statreset`
+///
+/// The first value covers an unknown interval, so it gives no rate, like the first value of `DiffCounter`.
+#[derive(Clone)]
+pub struct IntervalCounter(CounterData<f64>);
+
+impl Counter<f64> for IntervalCounter {
 	fn update(&mut self, new_value: f64, ms: usize) -> Result<f64, Box<dyn Error + Send + Sync>> {
 		let old_value = self.0.cur_value;
-		let diff = if old_value.is_nan() { f64::NAN } else { new_value - old_value };
 		self.0.cur_value = new_value;
 		match ms {
 			0 => Err("division by zero".to_owned().into()),
-			_ => Ok(diff / (ms as f64)),
+			_ if old_value.is_nan() => Ok(f64::NAN),
+			_ => Ok(new_value / (ms as f64)),
 		}
 	}
 
@@ -73,175 +167,1433 @@ impl Counter<f64> for DiffCounter {
 	fn cur_value(&self) -> f64 {
 		self.0.cur_value
 	}
+
+	fn reset(&mut self) {
+		self.0.cur_value = f64::NAN;
+	}
+
+	fn box_clone(&self) -> Box<dyn Counter<f64> + Send + Sync> {
+		Box::new(Self(self.0.clone()))
+	}
+
+	/// The whole value is the change over the interval
+	fn last_delta(&self) -> Option<f64> {
+		Some(self.0.cur_value).filter(|value| !value.is_nan())
+	}
+}
+
+impl Clone for Box<dyn Counter<f64> + Send + Sync> {
+	fn clone(&self) -> Self {
+		self.box_clone()
+	}
+}
+
+/// Counts are multiplied by this before being divided by the elapsed milliseconds, so rates are per second
+pub const RATE_SCALE: f64 = 1000.0;
+
+/// Returns a counter turning raw counts into rates, `reset` is set for counters zeroed after each read
+fn rate_counter(reset: bool, label: &'static str) -> Box<dyn Counter<f64> + Send + Sync> {
+	if reset {
+		Box::new(IntervalCounter::new(label))
+	} else {
+		Box::new(DiffCounter::new(label))
+	}
+}
+
+/// Upper bounds (in seconds) of scan time histogram buckets, the `+Inf` bucket is implied
+pub const SCAN_TIME_BUCKETS: [f64; 5] = [0.1, 0.25, 0.5, 1.0, 2.0];
+
+/// A counter that distributes observed values into buckets
+///
+/// Values are cumulative counts per bucket, like Prometheus `le` buckets, the last one being `+Inf`.
+#[derive(Clone, Debug)]
+pub struct HistogramCounter {
+	label: &'static str,
+	bounds: Vec<f64>,
+	buckets: Vec<u64>,
+	/// Sum and number of all observed values
+	pub sum: f64,
+	pub count: u64,
+}
+
+impl HistogramCounter {
+	pub fn with_bounds(label: &'static str, bounds: &[f64]) -> Self {
+		Self { label, bounds: bounds.to_vec(), buckets: vec![0; bounds.len() + 1], sum: 0.0, count: 0 }
+	}
+
+	/// Upper bounds of buckets except `+Inf`
+	pub fn bounds(&self) -> &[f64] {
+		&self.bounds
+	}
+
+	/// Adds observations of another histogram, histograms with different bounds are not merged
+	fn merge(&mut self, other: &HistogramCounter) {
+		if self.bounds == other.bounds {
+			for (bucket, other) in self.buckets.iter_mut().zip(&other.buckets) {
+				*bucket += other;
+			}
+			self.sum += other.sum;
+			self.count += other.count;
+		}
+	}
+}
+
+impl Counter<Vec<f64>> for HistogramCounter {
+	/// Observes all values, returning the updated cumulative bucket counts
+	fn update(&mut self, new_value: Vec<f64>, _ms: usize) -> Result<Vec<f64>, Box<dyn Error + Send + Sync>> {
+		for value in new_value.into_iter().filter(|value| value.is_finite()) {
+			let bucket = self
+				.bounds
+				.iter()
+				.position(|&bound| value <= bound)
+				.unwrap_or(self.bounds.len());
+			self.buckets[bucket] += 1;
+			self.sum += value;
+			self.count += 1;
+		}
+
+		Ok(self.cur_value())
+	}
+
+	fn new(label: &'static str) -> Self {
+		Self::with_bounds(label, &SCAN_TIME_BUCKETS)
+	}
+
+	fn label(&self) -> &'static str {
+		self.label
+	}
+
+	fn cur_value(&self) -> Vec<f64> {
+		self.buckets
+			.iter()
+			.scan(0_u64, |acc, &cnt| {
+				*acc += cnt;
+				Some(*acc as f64)
+			})
+			.collect()
+	}
+
+	fn reset(&mut self) {
+		self.buckets.iter_mut().for_each(|cnt| *cnt = 0);
+		self.sum = 0.0;
+		self.count = 0;
+	}
+
+	fn box_clone(&self) -> Box<dyn Counter<Vec<f64>> + Send + Sync> {
+		Box::new(self.clone())
+	}
 }
 
 /// Counters we support
-#[derive(Clone, Copy)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum KnownCounter {
 	Ham,
 	Spam,
 	Junk,
 	Total,
 	AvgTime,
+	ScanP50,
+	ScanP95,
+	ScanP99,
+	Scanned,
+	Learned,
+	Connections,
+	HamPercentage,
+	/// Memory allocated by Rspamd memory pools
+	BytesAllocated,
+	/// Pool allocations too large for a chunk, a growing rate hints at memory pressure
+	ChunksOversized,
+	/// Metric computed by a function registered with `RspamdStat::register_derived`
+	Derived(Arc<str>),
 	Unknown,
 }
 
-impl From<KnownCounter> for &'static str {
-	fn from(a: KnownCounter) -> &'static str {
+/// Label of the counter, derived metrics are labelled by their name, see `RspamdStatElement::label`
+impl From<&KnownCounter> for &'static str {
+	fn from(a: &KnownCounter) -> &'static str {
 		match a {
 			KnownCounter::Ham => "ham msg/sec",
 			KnownCounter::Spam => "spam msg/sec",
 			KnownCounter::Junk => "junk msg/sec",
 			KnownCounter::Total => "total msg/sec",
 			KnownCounter::AvgTime => "average_time sec",
+			KnownCounter::ScanP50 => "scan_time p50 sec",
+			KnownCounter::ScanP95 => "scan_time p95 sec",
+			KnownCounter::ScanP99 => "scan_time p99 sec",
+			KnownCounter::Scanned => "scanned msg/sec",
+			KnownCounter::Learned => "learned msg/sec",
+			KnownCounter::Connections => "connections conn/sec",
+			KnownCounter::HamPercentage => "ham percentage %",
+			KnownCounter::BytesAllocated => "bytes_allocated bytes",
+			KnownCounter::ChunksOversized => "chunks_oversized chunks/sec",
+			KnownCounter::Derived(_) => "derived",
+			KnownCounter::Unknown => "unknown",
+		}
+	}
+}
+
+impl KnownCounter {
+	/// Short name used to select a counter from the command line
+	pub fn name(&self) -> &str {
+		match self {
+			KnownCounter::Ham => "ham",
+			KnownCounter::Spam => "spam",
+			KnownCounter::Junk => "junk",
+			KnownCounter::Total => "total",
+			KnownCounter::AvgTime => "avg_time",
+			KnownCounter::ScanP50 => "scan_p50",
+			KnownCounter::ScanP95 => "scan_p95",
+			KnownCounter::ScanP99 => "scan_p99",
+			KnownCounter::Scanned => "scanned",
+			KnownCounter::Learned => "learned",
+			KnownCounter::Connections => "connections",
+			KnownCounter::HamPercentage => "ham_percentage",
+			KnownCounter::BytesAllocated => "bytes_allocated",
+			KnownCounter::ChunksOversized => "chunks_oversized",
+			KnownCounter::Derived(name) => name.as_ref(),
 			KnownCounter::Unknown => "unknown",
 		}
 	}
+
+	/// Unit of the counter values
+	pub fn unit(&self) -> &'static str {
+		match self {
+			KnownCounter::AvgTime | KnownCounter::ScanP50 | KnownCounter::ScanP95 | KnownCounter::ScanP99 => "sec",
+			KnownCounter::Connections => "conn/sec",
+			KnownCounter::HamPercentage => "%",
+			KnownCounter::BytesAllocated => "bytes",
+			KnownCounter::ChunksOversized => "chunks/sec",
+			KnownCounter::Derived(_) | KnownCounter::Unknown => "",
+			_ => "msg/sec",
+		}
+	}
+
+	/// Finds a counter by its short name
+	pub fn from_name(name: &str) -> Option<Self> {
+		[
+			KnownCounter::Spam,
+			KnownCounter::Ham,
+			KnownCounter::Junk,
+			KnownCounter::Total,
+			KnownCounter::AvgTime,
+			KnownCounter::ScanP50,
+			KnownCounter::ScanP95,
+			KnownCounter::ScanP99,
+			KnownCounter::Scanned,
+			KnownCounter::Learned,
+			KnownCounter::Connections,
+			KnownCounter::HamPercentage,
+			KnownCounter::BytesAllocated,
+			KnownCounter::ChunksOversized,
+		]
+		.into_iter()
+		.find(|counter| counter.name() == name)
+	}
 }
 
 impl From<&'static str> for KnownCounter {
 	fn from(s: &'static str) -> Self {
-		match s {
+		match normalize_action_key(s).as_str() {
 			"no action" => KnownCounter::Ham,
-			"no_action" => KnownCounter::Ham,
 			"total" => KnownCounter::Total,
-			"add header" => KnownCounter::Junk,
-			"add_header" => KnownCounter::Junk,
-			"rewrite subject" => KnownCounter::Junk,
-			"rewrite_subject" => KnownCounter::Junk,
+			"add header" | "rewrite subject" => KnownCounter::Junk,
 			_ => KnownCounter::Unknown,
 		}
 	}
 }
 
+/// Brings an action name to one spelling, Rspamd versions use both `no action` and `no_action`
+pub fn normalize_action_key(key: &str) -> String {
+	key.trim().replace('_', " ").to_lowercase()
+}
+
+/// Returns actions with normalized names, summing counts of actions spelled differently
+fn normalize_actions(actions: &serde_json::Value) -> serde_json::Value {
+	let mut normalized = serde_json::Map::new();
+
+	for (action, value) in actions.as_object().into_iter().flatten() {
+		let key = normalize_action_key(action);
+		let sum = normalized.get(&key).and_then(json_u64).unwrap_or(0) + json_u64(value).unwrap_or(0);
+		normalized.insert(key, sum.into());
+	}
+
+	serde_json::Value::Object(normalized)
+}
+
+/// Counters chosen to be shown and exported
+#[derive(Clone, Debug, PartialEq)]
+pub struct CounterSelection(pub Vec<KnownCounter>);
+
+impl CounterSelection {
+	/// Derived metrics are registered explicitly, so they are always selected
+	pub fn contains(&self, counter: &KnownCounter) -> bool {
+		matches!(counter, KnownCounter::Derived(_)) || self.0.contains(counter)
+	}
+}
+
+impl FromStr for CounterSelection {
+	type Err = String;
+
+	/// Parses a comma separated list of counter names, e.g. `spam,ham,total`
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let counters = s
+			.split(',')
+			.map(str::trim)
+			.filter(|name| !name.is_empty())
+			.map(|name| KnownCounter::from_name(name).ok_or_else(|| format!("unknown counter: {}", name)))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		if counters.is_empty() {
+			return Err("no counters selected".to_owned());
+		}
+
+		Ok(Self(counters))
+	}
+}
+
+/// Description of a counter for consumers that enumerate counters dynamically
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CounterMeta {
+	/// Short name, as accepted on the command line
+	pub name: String,
+	/// Human readable label
+	pub label: String,
+	/// Unit of values
+	pub unit: &'static str,
+	/// The latest value in the window, if any
+	pub current: Option<f64>,
+	/// Values in the window, the oldest first
+	pub window: Vec<f64>,
+}
+
 /// Used to track each action
 pub struct RspamdStatElement {
 	pub values: VecDeque<f64>,
-	pub counter: Box<dyn Counter<f64> + Send>,
+	pub counter: Box<dyn Counter<f64> + Send + Sync>,
+	pub kind: KnownCounter,
 	pub nelts: usize,
+	/// Last value shown on the screen
+	pub last_rendered: Option<f64>,
+	/// Number of values pushed since the start
+	pub pushed: u64,
+	/// Event markers as indexes of marked values since the start
+	pub markers: Vec<u64>,
+	/// Aggregates of all values pushed since the start
+	pub session: SessionStats,
+	/// Whether the counter was ever fed, counters missing from the stat never are
+	pub seen: bool,
+	/// Raw count since the start, or since the last Rspamd restart with `since_restart`; unset for gauges
+	pub since_start: Option<f64>,
+}
+
+/// Running aggregates of a counter over the whole session
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SessionStats {
+	pub count: u64,
+	pub sum: f64,
+	pub min: f64,
+	pub max: f64,
+}
+
+impl SessionStats {
+	/// Accounts a new value
+	pub fn add(&mut self, value: f64) {
+		if self.count == 0 {
+			self.min = value;
+			self.max = value;
+		} else {
+			self.min = self.min.min(value);
+			self.max = self.max.max(value);
+		}
+
+		self.count += 1;
+		self.sum += value;
+	}
+}
+
+/// Final report of a counter
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CounterSummary {
+	pub name: String,
+	pub min: Option<f64>,
+	pub avg: Option<f64>,
+	pub max: Option<f64>,
+	pub last: Option<f64>,
+}
+
+/// Final report of a run
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SessionSummary {
+	/// Duration of the run in seconds
+	pub duration: f64,
+	pub scrapes: u64,
+	pub errors: u64,
+	pub counters: Vec<CounterSummary>,
+}
+
+impl Clone for RspamdStatElement {
+	fn clone(&self) -> Self {
+		Self {
+			values: self.values.clone(),
+			counter: self.counter.box_clone(),
+			kind: self.kind.clone(),
+			nelts: self.nelts,
+			last_rendered: self.last_rendered,
+			pushed: self.pushed,
+			markers: self.markers.clone(),
+			session: self.session,
+			seen: self.seen,
+			since_start: self.since_start,
+		}
+	}
 }
 
 impl RspamdStatElement {
 	/// Creates a new stat element
 	pub fn new(nelts: usize, action: KnownCounter, is_gauge: bool) -> Self {
-		let counter: Box<dyn Counter<f64> + Send> = if is_gauge {
-			Box::new(GaugeCounter::new(action.into()))
+		let counter: Box<dyn Counter<f64> + Send + Sync> = if is_gauge {
+			Box::new(GaugeCounter::new((&action).into()))
 		} else {
-			Box::new(DiffCounter::new(action.into()))
+			Box::new(DiffCounter::new((&action).into()))
 		};
 
-		Self { values: VecDeque::with_capacity(nelts), counter, nelts }
+		Self::with_counter(nelts, action, counter)
+	}
+
+	/// Creates a new stat element updated by a specific counter
+	pub fn with_counter(nelts: usize, action: KnownCounter, counter: Box<dyn Counter<f64> + Send + Sync>) -> Self {
+		Self {
+			values: VecDeque::with_capacity(nelts),
+			counter,
+			kind: action,
+			nelts,
+			last_rendered: None,
+			pushed: 0,
+			markers: Vec::new(),
+			session: SessionStats::default(),
+			seen: false,
+			since_start: None,
+		}
+	}
+
+	/// Human readable label, the name for derived metrics
+	pub fn label(&self) -> &str {
+		match &self.kind {
+			KnownCounter::Derived(name) => name.as_ref(),
+			_ => self.counter.label(),
+		}
 	}
 
 	pub fn update(&mut self, value: f64, elapsed: Duration) -> Result<f64, Box<dyn Error + Send + Sync>> {
 		let ms = elapsed.as_millis() as usize;
+		self.seen = true;
 		let nv = self.counter.update(value, ms)?;
 
 		if !nv.is_nan() {
-			// Expire one
-			if self.values.len() >= self.nelts {
-				self.values.pop_front();
-			}
-
-			self.values.push_back(nv);
+			self.push(nv);
+		}
+		if let Some(delta) = self.last_delta() {
+			*self.since_start.get_or_insert(0.0) += delta;
 		}
 
 		Ok(nv)
 	}
 
+	/// Raw count change on the last update, e.g. the number of messages in the last tick
+	pub fn last_delta(&self) -> Option<f64> {
+		self.counter.last_delta().map(|delta| delta / RATE_SCALE)
+	}
+
+	/// Appends a value computed elsewhere, expiring the oldest one
+	pub fn push(&mut self, value: f64) {
+		if self.values.len() >= self.nelts {
+			self.values.pop_front();
+		}
+
+		self.values.push_back(value);
+		self.pushed += 1;
+		self.seen = true;
+		self.session.add(value);
+
+		// Forget markers that went out of the window
+		let first = self.pushed.saturating_sub(self.values.len() as u64);
+		self.markers.retain(|&marker| marker >= first);
+	}
+
+	/// Marks the latest value, so it could be highlighted on the chart
+	pub fn add_marker(&mut self) {
+		if self.pushed > 0 {
+			self.markers.push(self.pushed - 1);
+		}
+	}
+
+	/// Returns positions of markers within `values`
+	pub fn marker_positions(&self) -> Vec<usize> {
+		let first = self.pushed.saturating_sub(self.values.len() as u64);
+		self.markers
+			.iter()
+			.filter(|&&marker| marker >= first)
+			.map(|&marker| (marker - first) as usize)
+			.collect()
+	}
+
+	/// Returns true once the counter has produced at least one value
+	pub fn has_sample(&self) -> bool {
+		self.pushed > 0
+	}
+
 	pub fn nelts(&self) -> usize {
 		self.nelts
 	}
 	pub fn cur_value(&self) -> f64 {
 		self.counter.cur_value()
 	}
+
+	/// Returns values smoothed by a simple moving average over up to `points` latest values, stored values are
+	/// kept intact; non-finite values are skipped, a window without finite values gives NaN
+	pub fn smoothed(&self, points: usize) -> Vec<f64> {
+		let points = points.max(1);
+		let values = self.values.iter().cloned().collect::<Vec<_>>();
+
+		(0..values.len())
+			.map(|i| {
+				let window = values[(i + 1).saturating_sub(points)..=i].iter().filter(|v| v.is_finite());
+				let count = window.clone().count();
+				if count == 0 {
+					f64::NAN
+				} else {
+					window.sum::<f64>() / count as f64
+				}
+			})
+			.collect()
+	}
+}
+
+/// Minimum number of stored elements, a chart needs at least two points to show a line
+pub const MIN_ELEMENTS: usize = 2;
+
+/// Parses number of stored elements, rejecting values below `MIN_ELEMENTS`
+pub fn parse_num_elements(s: &str) -> Result<usize, String> {
+	let nelts = s
+		.trim()
+		.parse::<usize>()
+		.map_err(|e| format!("invalid number of elements {}: {}", s, e))?;
+
+	if nelts < MIN_ELEMENTS {
+		return Err(format!("number of elements must be at least {}, got {}", MIN_ELEMENTS, nelts));
+	}
+
+	Ok(nelts)
+}
+
+/// Computes a custom metric from the fetched JSON and the already updated stats, returning its name and value
+pub type DeriveFn = Arc<dyn Fn(&serde_json::Value, &RspamdStat) -> Option<(String, f64)> + Send + Sync>;
+
+/// Chart an Rspamd action is counted in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActionCategory {
+	Spam,
+	Ham,
+	Junk,
+	/// Not counted in any chart
+	Ignore,
+}
+
+impl FromStr for ActionCategory {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"spam" => Ok(ActionCategory::Spam),
+			"ham" => Ok(ActionCategory::Ham),
+			"junk" => Ok(ActionCategory::Junk),
+			"ignore" => Ok(ActionCategory::Ignore),
+			_ => Err(format!("unknown category: {}, expected spam, ham, junk or ignore", s)),
+		}
+	}
+}
+
+/// Override of an action category, parsed from `action=category`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActionMapping {
+	pub action: String,
+	pub category: ActionCategory,
+}
+
+impl FromStr for ActionMapping {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (action, category) = s.rsplit_once('=').ok_or_else(|| format!("expected action=category: {}", s))?;
+		let action = action.trim();
+		if action.is_empty() {
+			return Err(format!("empty action name: {}", s));
+		}
+
+		Ok(ActionMapping { action: normalize_action_key(action), category: category.trim().parse()? })
+	}
+}
+
+/// Groups Rspamd actions into spam, ham and junk charts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActionMap {
+	actions: Vec<(String, ActionCategory)>,
+}
+
+impl Default for ActionMap {
+	fn default() -> Self {
+		let actions = [
+			("reject", ActionCategory::Spam),
+			("no action", ActionCategory::Ham),
+			("add header", ActionCategory::Junk),
+			("rewrite subject", ActionCategory::Junk),
+		];
+
+		Self {
+			actions: actions
+				.into_iter()
+				.map(|(action, category)| (action.to_owned(), category))
+				.collect(),
+		}
+	}
+}
+
+impl ActionMap {
+	/// Default grouping with overrides applied in order
+	pub fn with_overrides(overrides: &[ActionMapping]) -> Self {
+		let mut map = Self::default();
+		for mapping in overrides {
+			map.assign(&mapping.action, mapping.category);
+		}
+
+		map
+	}
+
+	/// Moves an action to another category
+	pub fn assign(&mut self, action: &str, category: ActionCategory) {
+		let action = normalize_action_key(action);
+		self.actions.retain(|(name, _)| *name != action);
+		if category != ActionCategory::Ignore {
+			self.actions.push((action, category));
+		}
+	}
+
+	/// Returns actions counted in a category
+	pub fn actions(&self, category: ActionCategory) -> Vec<&str> {
+		self.actions
+			.iter()
+			.filter(|(_, cat)| *cat == category)
+			.map(|(name, _)| name.as_str())
+			.collect()
+	}
+}
+
+/// What drives the `total` chart
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TotalSource {
+	/// Sum of spam, ham and junk actions, greylisted and soft rejected messages are not counted
+	#[default]
+	ActionSum,
+	/// Top-level `scanned` counter, includes messages with any action, falls back to the action sum when missing
+	Scanned,
+}
+
+impl FromStr for TotalSource {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"action-sum" => Ok(TotalSource::ActionSum),
+			"scanned" => Ok(TotalSource::Scanned),
+			_ => Err(format!("unknown total source: {}, expected action-sum or scanned", s)),
+		}
+	}
 }
 
 /// Structure that holds all elements
+#[derive(Clone)]
 pub struct RspamdStat {
 	pub spam_stats: RspamdStatElement,
 	pub ham_stats: RspamdStatElement,
 	pub junk_stats: RspamdStatElement,
 	pub total: RspamdStatElement,
 	pub avg_time: RspamdStatElement,
+	pub scan_p50: RspamdStatElement,
+	pub scan_p95: RspamdStatElement,
+	pub scan_p99: RspamdStatElement,
+	pub scanned_stats: RspamdStatElement,
+	pub learned_stats: RspamdStatElement,
+	pub connections_stats: RspamdStatElement,
+	pub ham_percentage: RspamdStatElement,
+	pub bytes_allocated: RspamdStatElement,
+	pub chunks_oversized: RspamdStatElement,
+	/// Outcomes of the recent scrapes, `false` for failures
+	pub scrapes: VecDeque<bool>,
+	/// Number of scrapes and failed scrapes since the start
+	pub scrapes_total: u64,
+	pub errors_total: u64,
+	/// Count `soft reject` (temporary deferral) as spam
+	pub spam_includes_soft_reject: bool,
+	/// Grouping of actions into spam, ham and junk
+	pub action_map: ActionMap,
+	/// Counters shown and exported, all of them if not set
+	pub selection: Option<CounterSelection>,
+	/// Counter used for the `total` chart
+	pub total_source: TotalSource,
+	/// Whether the `total` counter was last fed with the action sum rather than `scanned`
+	total_from_actions: bool,
+	/// Raw cumulative number of scanned messages
+	pub scanned: Option<u64>,
+	/// Raw cumulative number of learned messages
+	pub learned: Option<u64>,
+	/// Distribution of scan times reported on each poll
+	pub scan_histogram: HistogramCounter,
+	/// Slots of the `scan_times` ring buffer from the previous poll, only changed slots are new scans
+	scan_slots: Vec<serde_json::Value>,
+	/// Numbers of values pushed to each element of each aggregated instance, only newer values are summed
+	instance_pushed: Vec<Vec<u64>>,
+	/// Rate of all actions together, including greylisting and soft rejects
+	pub actions_rate: Option<f64>,
+	all_actions: Box<dyn Counter<f64> + Send + Sync>,
+	/// Rate of each action by its normalized name, actions missing from the latest stat are left out
+	pub action_rates: BTreeMap<String, f64>,
+	action_counters: BTreeMap<String, Box<dyn Counter<f64> + Send + Sync>>,
+	/// Export `action_rates` as labelled series besides the spam, ham and junk buckets
+	pub export_action_rates: bool,
+	/// Counters are read from `/statreset`, so each poll reports only the last interval
+	pub reset: bool,
+	/// Start the since-start totals over when Rspamd is restarted
+	pub since_restart: bool,
+	/// Uptime from the previous stat, a drop means a restart
+	uptime: Option<u64>,
+	/// Configured interval of the latest poll, a hint for scrapers of the exported metrics
+	pub poll_interval: Option<Duration>,
+	/// Weights of the health score shown and exported
+	pub health_weights: HealthWeights,
+	/// Functions computing derived metrics on each tick
+	derivers: Vec<DeriveFn>,
+	/// Charts of derived metrics, created when a metric is reported for the first time
+	pub derived: Vec<RspamdStatElement>,
 }
 
 impl RspamdStat {
-	/// Create new stats object
-	pub fn new(nelts: usize) -> Self {
+	/// Create new stats object, `reset` is set when polling `/statreset`, which zeroes counters after each read
+	pub fn new(nelts: usize, reset: bool) -> Self {
+		let rate = |counter: KnownCounter| {
+			let label = (&counter).into();
+			RspamdStatElement::with_counter(nelts, counter, rate_counter(reset, label))
+		};
+
 		Self {
-			spam_stats: RspamdStatElement::new(nelts, KnownCounter::Spam, false),
-			ham_stats: RspamdStatElement::new(nelts, KnownCounter::Ham, false),
-			junk_stats: RspamdStatElement::new(nelts, KnownCounter::Junk, false),
-			total: RspamdStatElement::new(nelts, KnownCounter::Total, false),
+			spam_stats: rate(KnownCounter::Spam),
+			ham_stats: rate(KnownCounter::Ham),
+			junk_stats: rate(KnownCounter::Junk),
+			total: rate(KnownCounter::Total),
 			avg_time: RspamdStatElement::new(nelts, KnownCounter::AvgTime, true),
+			scan_p50: RspamdStatElement::new(nelts, KnownCounter::ScanP50, true),
+			scan_p95: RspamdStatElement::new(nelts, KnownCounter::ScanP95, true),
+			scan_p99: RspamdStatElement::new(nelts, KnownCounter::ScanP99, true),
+			scanned_stats: rate(KnownCounter::Scanned),
+			learned_stats: rate(KnownCounter::Learned),
+			connections_stats: rate(KnownCounter::Connections),
+			ham_percentage: RspamdStatElement::new(nelts, KnownCounter::HamPercentage, true),
+			bytes_allocated: RspamdStatElement::new(nelts, KnownCounter::BytesAllocated, true),
+			chunks_oversized: rate(KnownCounter::ChunksOversized),
+			scrapes: VecDeque::with_capacity(nelts),
+			scrapes_total: 0,
+			errors_total: 0,
+			spam_includes_soft_reject: false,
+			action_map: ActionMap::default(),
+			selection: None,
+			total_source: TotalSource::default(),
+			total_from_actions: false,
+			scanned: None,
+			learned: None,
+			scan_histogram: HistogramCounter::new("scan_time"),
+			scan_slots: Vec::new(),
+			instance_pushed: Vec::new(),
+			actions_rate: None,
+			all_actions: rate_counter(reset, "all actions"),
+			action_rates: BTreeMap::new(),
+			action_counters: BTreeMap::new(),
+			export_action_rates: false,
+			reset,
+			since_restart: false,
+			uptime: None,
+			poll_interval: None,
+			health_weights: HealthWeights::default(),
+			derivers: Vec::new(),
+			derived: Vec::new(),
 		}
 	}
 
-	/// Update stats from JSON received from Rspamd
+	/// Registers a function computing a derived metric, it is charted and exported as a gauge after each ingest
+	pub fn register_derived<F>(&mut self, derive: F)
+	where
+		F: Fn(&serde_json::Value, &RspamdStat) -> Option<(String, f64)> + Send + Sync + 'static,
+	{
+		self.derivers.push(Arc::new(derive));
+	}
+
+	/// Returns `scan_times` slots that changed since the previous poll, remembering the current ones
+	fn new_scan_slots(&mut self, scan_times: &serde_json::Value) -> serde_json::Value {
+		let slots = scan_times.as_array().cloned().unwrap_or_default();
+		let changed = slots
+			.iter()
+			.enumerate()
+			.filter(|&(i, slot)| self.scan_slots.get(i) != Some(slot))
+			.map(|(_, slot)| slot.clone())
+			.collect();
+		self.scan_slots = slots;
+
+		serde_json::Value::Array(changed)
+	}
+
+	/// Runs derived metric functions and stores their values
+	fn update_derived(&mut self, json: &serde_json::Value) {
+		let values = self.derivers.iter().filter_map(|derive| derive(json, self)).collect::<Vec<_>>();
+
+		for (name, value) in values.into_iter().filter(|(_, value)| value.is_finite()) {
+			match self.derived.iter_mut().find(|elt| elt.kind.name() == name) {
+				Some(elt) => elt.push(value),
+				None => {
+					let mut elt =
+						RspamdStatElement::new(self.spam_stats.nelts(), KnownCounter::Derived(name.into()), true);
+					elt.push(value);
+					self.derived.push(elt);
+				},
+			}
+		}
+	}
+
+	/// Update stats from JSON received from Rspamd, an array of per-instance objects is aggregated
 	pub fn update_from_json(
 		&mut self,
 		json: serde_json::Value,
 		elapsed: Duration,
 	) -> Result<(), Box<dyn Error + Send + Sync>> {
-		let actions = json.get("actions").ok_or(eyre!("missing actions"))?;
-		let spam_cnt =
-			update_specific_from_json(&mut self.spam_stats, actions, ["reject"].as_slice(), elapsed, 1000.0_f64)?;
-		let ham_cnt =
-			update_specific_from_json(&mut self.ham_stats, actions, ["no action"].as_slice(), elapsed, 1000.0_f64)?;
+		self.ingest(&json, elapsed)
+	}
+
+	/// Feeds stat JSON fetched elsewhere, e.g. by the embedder's own HTTP layer
+	///
+	/// `elapsed` is the time passed since the previous ingested sample.
+	pub fn ingest(&mut self, json: &serde_json::Value, elapsed: Duration) -> Result<(), Box<dyn Error + Send + Sync>> {
+		let merged;
+		let json = match json {
+			serde_json::Value::Array(instances) => {
+				merged = merge_instances(instances);
+				&merged
+			},
+			json => json,
+		};
+		// Rspamd may keep its counters in a file over restarts, so the uptime is checked besides decreasing counters
+		let uptime = json.get("uptime").and_then(json_u64);
+		if matches!((self.uptime, uptime), (Some(prev), Some(cur)) if cur < prev) {
+			self.restart_detected();
+		}
+		self.uptime = uptime;
+		let actions = &normalize_actions(json.get("actions").ok_or(eyre!("missing actions"))?);
+		let mut action_map = self.action_map.clone();
+		if self.spam_includes_soft_reject {
+			action_map.assign("soft reject", ActionCategory::Spam);
+		}
+		let spam_cnt = update_specific_from_json(
+			&mut self.spam_stats,
+			actions,
+			&action_map.actions(ActionCategory::Spam),
+			elapsed,
+			RATE_SCALE,
+		)?;
+		let ham_cnt = update_specific_from_json(
+			&mut self.ham_stats,
+			actions,
+			&action_map.actions(ActionCategory::Ham),
+			elapsed,
+			RATE_SCALE,
+		)?;
 		let junk_cnt = update_specific_from_json(
 			&mut self.junk_stats,
 			actions,
-			["add header", "rewrite subject"].as_slice(),
+			&action_map.actions(ActionCategory::Junk),
 			elapsed,
-			1000.0_f64,
+			RATE_SCALE,
 		)?;
-		self.total.update((spam_cnt + ham_cnt + junk_cnt) as f64, elapsed)?;
+		let (total_cnt, from_actions) = match (self.total_source, json.get("scanned").and_then(json_u64)) {
+			(TotalSource::Scanned, Some(scanned)) => (scanned as f64 * RATE_SCALE, false),
+			(TotalSource::Scanned, None) => {
+				debug!("no scanned counter in stat, using the sum of actions as total");
+				(spam_cnt + ham_cnt + junk_cnt, true)
+			},
+			(TotalSource::ActionSum, _) => (spam_cnt + ham_cnt + junk_cnt, true),
+		};
+		// The two sources cannot be compared, so the first tick after a switch only sets a new baseline
+		if from_actions != self.total_from_actions {
+			self.total.counter.reset();
+			self.total_from_actions = from_actions;
+		}
+		let total_rate = self.total.update(total_cnt, elapsed)?;
+		let all_actions = actions
+			.as_object()
+			.map(|actions| actions.values().filter_map(json_u64).sum::<u64>())
+			.unwrap_or(0);
+		let actions_rate = self
+			.all_actions
+			.update(all_actions as f64 * RATE_SCALE, elapsed.as_millis() as usize)?;
+		self.actions_rate = Some(actions_rate).filter(|rate| rate.is_finite());
+
+		// Every action gets its own rate, so it can be exported apart from the spam, ham and junk buckets
+		let mut action_rates = BTreeMap::new();
+		for (action, value) in actions.as_object().into_iter().flatten() {
+			let reset = self.reset;
+			let counter = self
+				.action_counters
+				.entry(action.clone())
+				.or_insert_with(|| rate_counter(reset, "action"));
+			let count = json_u64(value).unwrap_or(0) as f64 * RATE_SCALE;
+			let rate = counter.update(count, elapsed.as_millis() as usize)?;
+			if rate.is_finite() {
+				action_rates.insert(action.clone(), rate);
+			}
+		}
+		self.action_rates = action_rates;
+
+		// The percentage follows the total, so it is expected as soon as the total is
+		self.ham_percentage.seen = true;
+		if !total_rate.is_nan() {
+			let ham_rate = self.ham_stats.values.back().cloned().unwrap_or(0.0);
+			// Idle ticks carry the last known percentage forward
+			if let Some(pct) = ham_percentage(ham_rate, total_rate).or(self.ham_percentage.values.back().cloned()) {
+				self.ham_percentage.push(pct);
+			}
+		}
 
 		if let Some(scan_times) = json.get("scan_times") {
-			if scan_times.is_array() {
-				let scan_times = scan_times.as_array().unwrap();
+			let avg_times = extract_scan_times(scan_times);
+			if !avg_times.is_empty() {
+				let new_scans = extract_scan_times(&self.new_scan_slots(scan_times));
+				self.scan_histogram.update(new_scans, elapsed.as_millis() as usize)?;
+				let cnt = avg_times.len() as f64;
+				let avg_time = avg_times.iter().copied().sum_with_accumulator::<Sum2<_>>() / cnt;
+				self.avg_time.update(avg_time, elapsed)?;
 
-				let avg_times = scan_times
-					.iter()
-					.map(|json_num| json_num.as_f64().unwrap_or(f64::NAN))
-					.filter(|num| !num.is_nan())
-					.collect::<Vec<_>>();
-				if !avg_times.is_empty() {
-					let cnt = avg_times.len() as f64;
-					let avg_time = avg_times.sum_with_accumulator::<Sum2<_>>() / cnt;
-					self.avg_time.update(avg_time, elapsed)?;
+				let percentiles = [(&mut self.scan_p50, 50.0), (&mut self.scan_p95, 95.0), (&mut self.scan_p99, 99.0)];
+				for (elt, p) in percentiles {
+					if let Some(value) = percentile(&avg_times, p) {
+						elt.update(value, elapsed)?;
+					}
 				}
 			}
 		}
 
+		self.scanned = json.get("scanned").and_then(json_u64);
+		self.learned = json.get("learned").and_then(json_u64);
+		let raw = [
+			(&mut self.scanned_stats, "scanned"),
+			(&mut self.learned_stats, "learned"),
+			(&mut self.connections_stats, "connections"),
+			(&mut self.chunks_oversized, "chunks_oversized"),
+		];
+		for (elt, field) in raw {
+			// Zero values are kept, so read-only nodes show a flat learning chart
+			if let Some(value) = json.get(field).and_then(json_u64) {
+				elt.update(value as f64 * RATE_SCALE, elapsed)?;
+			}
+		}
+		// Allocated memory is read as is, so the chart shows the current value rather than the previous one
+		if let Some(bytes) = json.get("bytes_allocated").and_then(json_u64) {
+			self.bytes_allocated.push(bytes as f64);
+		}
+		self.update_derived(json);
+		if self.elements().iter().any(|elt| elt.counter.restarted()) {
+			self.restart_detected();
+		}
+
 		Ok(())
 	}
 
-	/// Display CLI plot
-	pub fn display_plot(&self, max_height: u16) {
-		prepare_term();
-		let mut next_graph_pos = 0_u16;
-		next_graph_pos = show_specific_counter(&self.spam_stats, next_graph_pos, max_height);
-		next_graph_pos = show_specific_counter(&self.ham_stats, next_graph_pos, max_height);
-		next_graph_pos = show_specific_counter(&self.junk_stats, next_graph_pos, max_height);
-		next_graph_pos = show_specific_counter(&self.total, next_graph_pos, max_height);
-		show_specific_counter(&self.avg_time, next_graph_pos, max_height);
-		finalise_term();
+	/// Starts the since-start totals over with `since_restart`, so they cover the current Rspamd uptime
+	fn restart_detected(&mut self) {
+		debug!("Rspamd restart detected");
+		if !self.since_restart {
+			return;
+		}
+		for elt in self.elements_mut() {
+			if let Some(total) = elt.since_start.as_mut() {
+				*total = 0.0;
+			}
+		}
 	}
-}
 
-/// Update specific counter from a JSON object, multiplying value by `mult`
-fn update_specific_from_json(
-	elt: &mut RspamdStatElement,
-	actions_json: &serde_json::Value,
-	field: &[&'static str],
+	/// Pushes sums of the fresh values of several instances polled together, so the window survives an instance
+	/// going down; `samples` holds stat documents of instances sampled on this tick and None for the others
+	///
+	/// Scan times are averaged over instances as they cannot be summed.
+	pub fn aggregate(&mut self, instances: &[RspamdStat], samples: &[Option<serde_json::Value>]) {
+		self.instance_pushed.resize(instances.len(), Vec::new());
+		// Fresh values of each element over all instances
+		let mut fresh: Vec<Vec<f64>> = Vec::new();
+		for (instance, pushed) in instances.iter().zip(self.instance_pushed.iter_mut()) {
+			let elements = instance.elements();
+			let elements = &elements[..elements.len() - instance.derived.len()];
+			fresh.resize(elements.len(), Vec::new());
+			pushed.resize(elements.len(), 0);
+			for ((elt, pushed), values) in elements.iter().zip(pushed.iter_mut()).zip(fresh.iter_mut()) {
+				if elt.pushed > *pushed {
+					values.extend(elt.values.back().cloned().filter(|value| value.is_finite()));
+				}
+				*pushed = elt.pushed;
+			}
+		}
+
+		let mut total_rate = None;
+		for (elt, values) in self.elements_mut().into_iter().zip(fresh) {
+			if values.is_empty() || elt.kind == KnownCounter::HamPercentage {
+				continue;
+			}
+			let sum = values.iter().copied().sum_with_accumulator::<Sum2<_>>();
+			match elt.kind {
+				KnownCounter::AvgTime | KnownCounter::ScanP50 | KnownCounter::ScanP95 | KnownCounter::ScanP99 => {
+					elt.push(sum / values.len() as f64)
+				},
+				_ => {
+					if elt.kind == KnownCounter::Total {
+						total_rate = Some(sum);
+					}
+					elt.push(sum)
+				},
+			}
+		}
+		if let Some(total_rate) = total_rate {
+			self.ham_percentage.seen = true;
+			let ham_rate = self.ham_stats.values.back().cloned().unwrap_or(0.0);
+			if let Some(pct) = ham_percentage(ham_rate, total_rate).or(self.ham_percentage.values.back().cloned()) {
+				self.ham_percentage.push(pct);
+			}
+		}
+
+		// Each instance restarts on its own, so it keeps its own totals, including instances which are down
+		let fixed = self.elements().len() - self.derived.len();
+		for (i, elt) in self.elements_mut().into_iter().enumerate().take(fixed) {
+			elt.since_start = instances
+				.iter()
+				.filter_map(|instance| instance.elements().get(i).and_then(|elt| elt.since_start))
+				.reduce(|a, b| a + b);
+		}
+
+		let sampled = instances
+			.iter()
+			.zip(samples)
+			.filter_map(|(instance, sample)| sample.as_ref().map(|_| instance))
+			.collect::<Vec<_>>();
+		if sampled.is_empty() {
+			return;
+		}
+		self.scanned = sampled.iter().filter_map(|instance| instance.scanned).reduce(|a, b| a + b);
+		self.learned = sampled.iter().filter_map(|instance| instance.learned).reduce(|a, b| a + b);
+		self.actions_rate = sampled.iter().filter_map(|instance| instance.actions_rate).reduce(|a, b| a + b);
+		let mut action_rates = BTreeMap::new();
+		for (action, rate) in sampled.iter().flat_map(|instance| &instance.action_rates) {
+			*action_rates.entry(action.clone()).or_insert(0.0) += rate;
+		}
+		self.action_rates = action_rates;
+		// Histograms are cumulative, so instances which are down keep their observations
+		let mut histogram = HistogramCounter::with_bounds(self.scan_histogram.label, &self.scan_histogram.bounds);
+		for instance in instances {
+			histogram.merge(&instance.scan_histogram);
+		}
+		self.scan_histogram = histogram;
+		let documents = samples.iter().flatten().cloned().collect::<Vec<_>>();
+		self.update_derived(&merge_instances(&documents));
+	}
+
+	/// Returns all elements in display order
+	pub fn elements(&self) -> Vec<&RspamdStatElement> {
+		let mut elements = vec![
+			&self.spam_stats,
+			&self.ham_stats,
+			&self.junk_stats,
+			&self.total,
+			&self.avg_time,
+			&self.scan_p50,
+			&self.scan_p95,
+			&self.scan_p99,
+			&self.scanned_stats,
+			&self.learned_stats,
+			&self.connections_stats,
+			&self.ham_percentage,
+			&self.bytes_allocated,
+			&self.chunks_oversized,
+		];
+		elements.extend(self.derived.iter());
+
+		elements
+	}
+
+	/// Whether a counter should be shown and exported
+	pub fn is_selected(&self, counter: &KnownCounter) -> bool {
+		match &self.selection {
+			Some(selection) => selection.contains(counter),
+			None => true,
+		}
+	}
+
+	/// Returns elements chosen by `selection` in display order
+	pub fn selected_elements(&self) -> Vec<&RspamdStatElement> {
+		self.elements().into_iter().filter(|elt| self.is_selected(&elt.kind)).collect()
+	}
+
+	/// Returns all elements for modification
+	pub fn elements_mut(&mut self) -> Vec<&mut RspamdStatElement> {
+		let mut elements = vec![
+			&mut self.spam_stats,
+			&mut self.ham_stats,
+			&mut self.junk_stats,
+			&mut self.total,
+			&mut self.avg_time,
+			&mut self.scan_p50,
+			&mut self.scan_p95,
+			&mut self.scan_p99,
+			&mut self.scanned_stats,
+			&mut self.learned_stats,
+			&mut self.connections_stats,
+			&mut self.ham_percentage,
+			&mut self.bytes_allocated,
+			&mut self.chunks_oversized,
+		];
+		elements.extend(self.derived.iter_mut());
+
+		elements
+	}
+
+	/// Returns description of all counters with their current windows
+	pub fn counters_meta(&self) -> Vec<CounterMeta> {
+		self.elements()
+			.into_iter()
+			.map(|elt| CounterMeta {
+				name: elt.kind.name().to_owned(),
+				label: elt.label().to_owned(),
+				unit: elt.kind.unit(),
+				current: elt.values.back().cloned(),
+				window: elt.values.iter().cloned().collect(),
+			})
+			.collect()
+	}
+
+	/// Returns an element for a specific counter
+	pub fn element(&self, counter: &KnownCounter) -> Option<&RspamdStatElement> {
+		self.elements().into_iter().find(|elt| elt.kind == *counter)
+	}
+
+	/// Display CLI plot
+	pub fn display_plot(&mut self, settings: &PlotSettings) {
+		let term_rows = term_rows().unwrap_or(settings.height + 3);
+		prepare_term();
+		let mut next_graph_pos = 0_u16;
+		let mut last_settings = settings.clone();
+		let mut rendered = Vec::new();
+		let layout = self
+			.plot_layout(settings, term_rows)
+			.into_iter()
+			.filter(|(elt, _)| !elt.values.is_empty())
+			.collect::<Vec<_>>();
+		let charts = align_charts(
+			layout
+				.iter()
+				.map(|(elt, elt_settings)| render_counter(elt, elt_settings))
+				.collect(),
+		);
+		for ((elt, elt_settings), chart) in layout.iter().zip(charts.iter()) {
+			next_graph_pos = show_specific_counter(chart, next_graph_pos, elt_settings);
+			last_settings = elt_settings.clone();
+			rendered.push(elt.kind.clone());
+		}
+		if let Some(summary) = self.summary(settings) {
+			show_summary(&summary, next_graph_pos, &last_settings);
+		}
+		finalise_term();
+		self.mark_rendered(&rendered);
+	}
+
+	/// Forgets current values of all counters keeping their history, e.g. when switching to another instance
+	pub fn reset_counters(&mut self) {
+		for elt in self.elements_mut() {
+			elt.counter.reset();
+		}
+		self.all_actions.reset();
+		self.actions_rate = None;
+		for counter in self.action_counters.values_mut() {
+			counter.reset();
+		}
+		self.action_rates.clear();
+		self.scan_slots.clear();
+	}
+
+	/// Marks the current tick on all charts
+	pub fn add_marker(&mut self) {
+		for elt in self.elements_mut() {
+			elt.add_marker();
+		}
+	}
+
+	/// Records an outcome of a scrape
+	pub fn record_scrape(&mut self, ok: bool) {
+		if self.scrapes.len() >= self.spam_stats.nelts() {
+			self.scrapes.pop_front();
+		}
+
+		self.scrapes.push_back(ok);
+		self.scrapes_total += 1;
+		if !ok {
+			self.errors_total += 1;
+		}
+	}
+
+	/// Summarizes the whole session, e.g. to be reported on exit
+	pub fn session_summary(&self, duration: Duration) -> SessionSummary {
+		let counters = self
+			.elements()
+			.into_iter()
+			.map(|elt| {
+				let session = (elt.session.count > 0).then_some(elt.session);
+				CounterSummary {
+					name: elt.kind.name().to_owned(),
+					min: session.map(|s| s.min),
+					avg: session.map(|s| s.sum / s.count as f64),
+					max: session.map(|s| s.max),
+					last: elt.values.back().cloned(),
+				}
+			})
+			.collect();
+
+		SessionSummary {
+			duration: duration.as_secs_f64(),
+			scrapes: self.scrapes_total,
+			errors: self.errors_total,
+			counters,
+		}
+	}
+
+	/// Share of failed recent scrapes
+	pub fn error_rate(&self) -> f64 {
+		if self.scrapes.is_empty() {
+			return 0.0;
+		}
+
+		self.scrapes.iter().filter(|ok| !**ok).count() as f64 / self.scrapes.len() as f64
+	}
+
+	/// Health score for exports, `None` until there are rates to judge by
+	pub fn health(&self) -> Option<f64> {
+		(!self.total.values.is_empty()).then(|| health_score(self, &self.health_weights))
+	}
+
+	/// Returns a line shown below the charts, if any
+	pub fn summary(&self, settings: &PlotSettings) -> Option<String> {
+		let mut parts = Vec::new();
+
+		if let Some((ready, total)) = self.collecting_progress() {
+			parts.push(format!("[collecting… {}/{} samples]", ready, total));
+		}
+		if settings.health {
+			parts.push(format!("[Health: {:.1}]", health_score(self, &self.health_weights)));
+		}
+		if settings.window_summary {
+			parts.push(self.window_summary());
+		}
+		if let Some((a, b)) = &settings.correlate {
+			parts.push(self.correlation_summary(a, b));
+		}
+
+		Some(parts.join(" ")).filter(|summary| !summary.is_empty())
+	}
+
+	/// Returns number of counters with samples and number of counters expected to get them, `None` when all of them
+	/// are ready
+	///
+	/// Once stats arrive, only counters present in them are expected, e.g. old Rspamd versions lack memory counters.
+	pub fn collecting_progress(&self) -> Option<(usize, usize)> {
+		let elements = self.selected_elements();
+		let ready = elements.iter().filter(|elt| elt.has_sample()).count();
+		let seen = elements.iter().filter(|elt| elt.seen).count();
+		let total = if seen == 0 { elements.len() } else { seen };
+
+		Some((ready, total)).filter(|&(ready, total)| ready < total)
+	}
+
+	/// Returns summary of the whole displayed window
+	pub fn window_summary(&self) -> String {
+		format!(
+			"[Window acceptance: {:.2}%] [Window rejection: {:.2}%]",
+			window_acceptance_rate(self) * 100.0,
+			window_rejection_rate(self) * 100.0
+		)
+	}
+
+	/// Returns correlation of two counters over the window
+	pub fn correlation_summary(&self, a: &KnownCounter, b: &KnownCounter) -> String {
+		let window = |counter: &KnownCounter| {
+			self.element(counter)
+				.map(|elt| elt.values.iter().cloned().collect::<Vec<_>>())
+				.unwrap_or_default()
+		};
+
+		match correlation(&window(a), &window(b)) {
+			Some(r) => format!("[corr({}, {}) = {:.2}]", a.name(), b.name(), r),
+			None => format!("[corr({}, {}) = n/a]", a.name(), b.name()),
+		}
+	}
+
+	/// Remembers currently displayed values, so the next frame can show the difference
+	pub fn mark_rendered(&mut self, rendered: &[KnownCounter]) {
+		for elt in self.elements_mut().into_iter().filter(|elt| rendered.contains(&elt.kind)) {
+			elt.last_rendered = elt.values.back().cloned();
+		}
+	}
+
+	/// Returns elements to be drawn with the settings for each of them
+	pub fn plot_layout(&self, settings: &PlotSettings, term_rows: u16) -> Vec<(&RspamdStatElement, PlotSettings)> {
+		match settings.focus.as_ref().and_then(|counter| self.element(counter)) {
+			Some(elt) => {
+				// Focused chart takes the whole terminal, leaving space for its caption and the summary
+				let reserved = if settings.has_summary() { 4 } else { 3 };
+				let height = term_rows.saturating_sub(reserved).max(1);
+				vec![(elt, PlotSettings { height, ..settings.clone() })]
+			},
+			None => {
+				let elements = self.selected_elements();
+				let range = match settings.charts_per_page {
+					Some(per_page) => page_range(elements.len(), per_page, settings.page),
+					None => 0..elements.len(),
+				};
+				elements[range].iter().map(|elt| (*elt, settings.clone())).collect()
+			},
+		}
+	}
+}
+
+/// Display CLI plots of several instances one below another, each caption is followed by the instance label
+pub fn display_stacked_plot(instances: &mut [RspamdStat], labels: &[String], settings: &PlotSettings) {
+	let term_rows = term_rows().unwrap_or(settings.height + 3) / instances.len().max(1) as u16;
+	prepare_term();
+	let mut next_graph_pos = 0_u16;
+	let layouts = instances
+		.iter()
+		.map(|stat| {
+			stat.plot_layout(settings, term_rows)
+				.into_iter()
+				.filter(|(elt, _)| !elt.values.is_empty())
+				.collect::<Vec<_>>()
+		})
+		.collect::<Vec<_>>();
+	// Aligned across instances, so the same ticks of all of them are one above another
+	let charts = align_charts(
+		layouts
+			.iter()
+			.zip(labels)
+			.flat_map(|(layout, label)| {
+				layout
+					.iter()
+					.map(move |(elt, elt_settings)| format!("{} [{}]", render_counter(elt, elt_settings), label))
+			})
+			.collect(),
+	);
+	let mut charts = charts.iter();
+	let mut rendered = Vec::with_capacity(layouts.len());
+	for layout in &layouts {
+		let mut kinds = Vec::with_capacity(layout.len());
+		for ((elt, elt_settings), chart) in layout.iter().zip(charts.by_ref()) {
+			next_graph_pos = show_specific_counter(chart, next_graph_pos, elt_settings);
+			kinds.push(elt.kind.clone());
+		}
+		rendered.push(kinds);
+	}
+	finalise_term();
+	for (stat, kinds) in instances.iter_mut().zip(rendered) {
+		stat.mark_rendered(&kinds);
+	}
+}
+
+/// Share of ham in all messages over the whole window, NaN if there were no messages
+pub fn window_acceptance_rate(stat: &RspamdStat) -> f64 {
+	window_ratio(&stat.ham_stats, &stat.total)
+}
+
+/// Share of spam in all messages over the whole window, NaN if there were no messages
+pub fn window_rejection_rate(stat: &RspamdStat) -> f64 {
+	window_ratio(&stat.spam_stats, &stat.total)
+}
+
+/// Share of ham in all messages of a tick in percents, `None` when there were no messages
+pub fn ham_percentage(ham_rate: f64, total_rate: f64) -> Option<f64> {
+	(total_rate > 0.0 && ham_rate.is_finite()).then(|| ham_rate / total_rate * 100.0)
+}
+
+/// Percentile of values using linear interpolation between the closest ranks, so it works for short series too
+pub fn percentile(values: &[f64], p: f64) -> Option<f64> {
+	let mut sorted = values.iter().cloned().filter(|v| v.is_finite()).collect::<Vec<_>>();
+	if sorted.is_empty() {
+		return None;
+	}
+	sorted.sort_by(f64::total_cmp);
+
+	let rank = p.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64;
+	let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+
+	Some(sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64))
+}
+
+/// Population standard deviation of values, NaN for an empty slice
+pub fn stddev(values: &[f64]) -> f64 {
+	if values.is_empty() {
+		return f64::NAN;
+	}
+
+	let cnt = values.len() as f64;
+	let mean = values.iter().sum::<f64>() / cnt;
+	let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / cnt;
+
+	variance.sqrt()
+}
+
+/// Pearson correlation of two series aligned by their tails, `None` if it is undefined
+pub fn correlation(a: &[f64], b: &[f64]) -> Option<f64> {
+	let len = a.len().min(b.len());
+	if len < 2 {
+		return None;
+	}
+
+	let (a, b) = (&a[a.len() - len..], &b[b.len() - len..]);
+	let mean_a = a.iter().sum::<f64>() / len as f64;
+	let mean_b = b.iter().sum::<f64>() / len as f64;
+	let cov = a.iter().zip(b).map(|(x, y)| (x - mean_a) * (y - mean_b)).sum::<f64>();
+	let var_a = a.iter().map(|x| (x - mean_a).powi(2)).sum::<f64>();
+	let var_b = b.iter().map(|y| (y - mean_b).powi(2)).sum::<f64>();
+
+	Some(cov / (var_a * var_b).sqrt()).filter(|r| r.is_finite())
+}
+
+fn window_ratio(part: &RspamdStatElement, total: &RspamdStatElement) -> f64 {
+	let total = total.values.iter().sum::<f64>();
+
+	if total > 0.0 {
+		part.values.iter().sum::<f64>() / total
+	} else {
+		f64::NAN
+	}
+}
+
+/// Update specific counter from a JSON object, multiplying value by `mult`
+fn update_specific_from_json(
+	elt: &mut RspamdStatElement,
+	actions_json: &serde_json::Value,
+	field: &[&str],
 	elapsed: Duration,
 	mult: f64,
 ) -> Result<f64, Box<dyn Error + Send + Sync>> {
 	let total = field.iter().fold(0_u64, |acc, field| {
 		let extracted = actions_json.get(field);
-		let extracted = extracted.map(|v| v.as_u64().unwrap_or(0_u64));
+		let extracted = extracted.map(|v| json_u64(v).unwrap_or(0_u64));
 		acc + extracted.unwrap_or(0_u64)
 	}) as f64
 		* mult;
@@ -249,9 +1601,156 @@ fn update_specific_from_json(
 	Ok(total)
 }
 
+/// Parses stat JSON, recovering from non-finite numbers (`NaN`, `Infinity`) by replacing them with nulls
+pub fn parse_stat_json(body: &[u8]) -> Result<serde_json::Value, serde_json::Error> {
+	serde_json::from_slice(body).or_else(|e| {
+		let body = String::from_utf8_lossy(body);
+		let lenient = replace_non_finite(&body);
+
+		if lenient == body {
+			return Err(e);
+		}
+
+		debug!("replaced non-finite numbers in stat JSON");
+		serde_json::from_str(&lenient).map_err(|_| e)
+	})
+}
+
+/// Replaces non-finite number literals outside of strings with `null`
+fn replace_non_finite(body: &str) -> String {
+	const NON_FINITE: [&str; 7] = ["-Infinity", "Infinity", "-NaN", "NaN", "-inf", "inf", "nan"];
+	let mut out = String::with_capacity(body.len());
+	let mut rest = body;
+	let mut in_string = false;
+	let mut escaped = false;
+
+	while let Some(c) = rest.chars().next() {
+		if in_string {
+			if escaped {
+				escaped = false;
+			} else if c == '\\' {
+				escaped = true;
+			} else if c == '"' {
+				in_string = false;
+			}
+		} else if c == '"' {
+			in_string = true;
+		} else if let Some(token) = NON_FINITE.iter().find(|token| rest.starts_with(**token)) {
+			out.push_str("null");
+			rest = &rest[token.len()..];
+			continue;
+		}
+
+		out.push(c);
+		rest = &rest[c.len_utf8()..];
+	}
+
+	out
+}
+
+/// Extracts scan times from either an array of numbers or an array of objects with the `time` field
+pub fn extract_scan_times(scan_times: &serde_json::Value) -> Vec<f64> {
+	scan_times
+		.as_array()
+		.map(|times| {
+			times
+				.iter()
+				.filter_map(|time| match time {
+					serde_json::Value::Object(obj) => obj.get("time").and_then(json_f64),
+					_ => json_f64(time),
+				})
+				.filter(|num| num.is_finite())
+				.collect()
+		})
+		.unwrap_or_default()
+}
+
+/// Top-level cumulative counters summed across instances
+const RAW_FIELDS: [&str; 5] = ["scanned", "learned", "connections", "bytes_allocated", "chunks_oversized"];
+
+/// Merges stats of several instances into one document, summing actions and joining scan times
+fn merge_instances(instances: &[serde_json::Value]) -> serde_json::Value {
+	let mut actions = serde_json::Map::new();
+	let mut scan_times = Vec::new();
+	let mut raw = serde_json::Map::new();
+
+	for instance in instances {
+		for field in RAW_FIELDS {
+			if let Some(value) = instance.get(field).and_then(json_u64) {
+				let sum = raw.get(field).and_then(json_u64).unwrap_or(0) + value;
+				raw.insert(field.to_owned(), sum.into());
+			}
+		}
+
+		if let Some(instance_actions) = instance.get("actions").and_then(|v| v.as_object()) {
+			for (action, value) in instance_actions {
+				let sum = actions.get(action).and_then(json_u64).unwrap_or(0) + json_u64(value).unwrap_or(0);
+				actions.insert(action.clone(), sum.into());
+			}
+		}
+
+		if let Some(instance_times) = instance.get("scan_times").and_then(|v| v.as_array()) {
+			scan_times.extend(instance_times.iter().cloned());
+		}
+	}
+
+	raw.insert("actions".to_owned(), actions.into());
+	raw.insert("scan_times".to_owned(), scan_times.into());
+	serde_json::Value::Object(raw)
+}
+
+/// Formats raw cumulative counters and uptime of a single stat document as `name value` lines, action names
+/// have spaces replaced with underscores, e.g. `action.no_action 1100`
+pub fn raw_summary(json: &serde_json::Value) -> String {
+	let mut lines = Vec::new();
+
+	for field in RAW_FIELDS {
+		if let Some(value) = json.get(field).and_then(json_u64) {
+			lines.push(format!("{} {}", field, value));
+		}
+	}
+	if let Some(actions) = json.get("actions").and_then(|v| v.as_object()) {
+		for (action, value) in actions {
+			if let Some(value) = json_u64(value) {
+				lines.push(format!("action.{} {}", action.replace(' ', "_"), value));
+			}
+		}
+	}
+	if let Some(uptime) = json.get("uptime").and_then(json_u64) {
+		lines.push(format!("uptime {}", uptime));
+	}
+
+	lines.join("\n")
+}
+
+/// Extracts an unsigned number from JSON, accepting numbers encoded as strings
+fn json_u64(value: &serde_json::Value) -> Option<u64> {
+	match value {
+		serde_json::Value::String(s) => s.trim().parse().ok(),
+		_ => value.as_u64(),
+	}
+}
+
+/// Extracts a finite floating point number from JSON, accepting numbers encoded as strings
+fn json_f64(value: &serde_json::Value) -> Option<f64> {
+	match value {
+		serde_json::Value::String(s) => s.trim().parse().ok(),
+		_ => value.as_f64(),
+	}
+	.filter(|num: &f64| num.is_finite())
+}
+
 #[cfg(test)]
 mod tests {
-	use crate::counters::{KnownCounter, RspamdStat, RspamdStatElement};
+	use crate::{
+		counters::{
+			correlation, extract_scan_times, ham_percentage, normalize_action_key, parse_num_elements, parse_stat_json,
+			percentile, raw_summary, stddev, window_acceptance_rate, window_rejection_rate, ActionCategory, ActionMap,
+			ActionMapping, Counter, CounterSelection, DiffCounter, HistogramCounter, KnownCounter, RspamdStat,
+			RspamdStatElement, TotalSource, RATE_SCALE, SCAN_TIME_BUCKETS,
+		},
+		plot::PlotSettings,
+	};
 	use std::time::Duration;
 
 	#[test]
@@ -270,6 +1769,106 @@ mod tests {
 		assert_eq!(ctr.values[1], 1_f64);
 	}
 
+	#[test]
+	fn diff_counter_reset_test() {
+		let mut ctr = RspamdStatElement::new(4, KnownCounter::Unknown, false);
+		let elapsed = Duration::from_millis(1);
+		assert!(ctr.update(100_f64, elapsed).unwrap().is_nan());
+		assert_eq!(ctr.update(110_f64, elapsed).unwrap(), 10_f64);
+		// Restart: the point is skipped and the new value becomes the baseline
+		assert!(ctr.update(3_f64, elapsed).unwrap().is_nan());
+		assert_eq!(ctr.update(5_f64, elapsed).unwrap(), 2_f64);
+		assert_eq!(ctr.values, [10_f64, 2_f64]);
+		assert!(ctr.values.iter().all(|&v| v >= 0_f64));
+	}
+
+	#[test]
+	fn since_restart_test() {
+		let elapsed = Duration::from_secs(1);
+		let stat_json = |reject: u64, uptime: u64| serde_json::json!({"actions": {"reject": reject}, "uptime": uptime});
+		let mut stat = RspamdStat::new(4, false);
+		stat.since_restart = true;
+		let mut kept = stat.clone();
+		kept.since_restart = false;
+		for stat in [&mut stat, &mut kept] {
+			stat.ingest(&stat_json(100, 10), elapsed).unwrap();
+			stat.ingest(&stat_json(130, 11), elapsed).unwrap();
+			assert_eq!(stat.spam_stats.since_start, Some(30_f64));
+		}
+
+		// Decreasing counters reset the totals
+		for stat in [&mut stat, &mut kept] {
+			stat.ingest(&stat_json(5, 12), elapsed).unwrap();
+			stat.ingest(&stat_json(15, 13), elapsed).unwrap();
+		}
+		assert_eq!(stat.spam_stats.since_start, Some(10_f64));
+		assert_eq!(kept.spam_stats.since_start, Some(40_f64));
+
+		// Counters kept in a file over a restart, only the uptime drops
+		for stat in [&mut stat, &mut kept] {
+			stat.ingest(&stat_json(20, 1), elapsed).unwrap();
+		}
+		assert_eq!(stat.spam_stats.since_start, Some(5_f64));
+		assert_eq!(stat.total.since_start, Some(5_f64));
+		assert_eq!(kept.spam_stats.since_start, Some(45_f64));
+	}
+
+	#[test]
+	fn last_delta_test() {
+		let mut ctr = DiffCounter::new("test");
+		assert_eq!(ctr.last_delta(), None);
+		ctr.update(100_f64, 1000).unwrap();
+		assert_eq!(ctr.last_delta(), None);
+		assert_eq!(ctr.update(137_f64, 1000).unwrap(), 0.037);
+		assert_eq!(ctr.last_delta(), Some(37_f64));
+		// The same change over a longer tick gives a lower rate, but the same delta
+		assert_eq!(ctr.update(174_f64, 4000).unwrap(), 0.00925);
+		assert_eq!(ctr.last_delta(), Some(37_f64));
+
+		let mut elt = RspamdStatElement::new(4, KnownCounter::Spam, false);
+		elt.update(100_f64 * RATE_SCALE, Duration::from_secs(1)).unwrap();
+		elt.update(137_f64 * RATE_SCALE, Duration::from_secs(3)).unwrap();
+		assert_eq!(elt.last_delta(), Some(37_f64));
+		assert_eq!(RspamdStatElement::new(4, KnownCounter::AvgTime, true).last_delta(), None);
+	}
+
+	#[test]
+	fn statreset_test() {
+		let mut stat = RspamdStat::new(4, true);
+		let elapsed = Duration::from_secs(2);
+		// The first read covers everything since the previous reset
+		let first = serde_json::json!({"scanned": 5000, "actions": {"reject": 1000, "no action": 4000}});
+		let second = serde_json::json!({"scanned": 30, "actions": {"reject": 10, "no action": 20}});
+		stat.ingest(&first, elapsed).unwrap();
+		assert!(stat.spam_stats.values.is_empty());
+		stat.ingest(&second, elapsed).unwrap();
+		stat.ingest(&second, elapsed).unwrap();
+
+		// Per-interval values are divided by the elapsed time, not differentiated
+		assert_eq!(stat.spam_stats.values, [5_f64, 5_f64]);
+		assert_eq!(stat.ham_stats.values, [10_f64, 10_f64]);
+		assert_eq!(stat.scanned_stats.values, [15_f64, 15_f64]);
+		assert_eq!(stat.actions_rate, Some(15_f64));
+	}
+
+	#[test]
+	fn smoothed_test() {
+		let mut elt = RspamdStatElement::new(8, KnownCounter::Spam, false);
+		elt.values.extend([3_f64, 9_f64, 0_f64, 6_f64, 3_f64]);
+
+		assert_eq!(elt.smoothed(3), vec![3_f64, 6_f64, 4_f64, 5_f64, 3_f64]);
+		assert_eq!(elt.smoothed(1), elt.values.iter().cloned().collect::<Vec<_>>());
+		assert_eq!(elt.smoothed(0), elt.smoothed(1));
+		// Raw values are not altered
+		assert_eq!(elt.values, [3_f64, 9_f64, 0_f64, 6_f64, 3_f64]);
+
+		// A non-finite value only affects the windows it is in
+		elt.values = [f64::NAN, 2_f64, f64::INFINITY, 4_f64, 6_f64].into_iter().collect();
+		let smoothed = elt.smoothed(2);
+		assert!(smoothed[0].is_nan());
+		assert_eq!(smoothed[1..], [2_f64, 2_f64, 4_f64, 5_f64]);
+	}
+
 	#[test]
 	fn gauge_counter_test() {
 		let mut ctr = RspamdStatElement::new(2, KnownCounter::Unknown, true);
@@ -286,13 +1885,624 @@ mod tests {
 		assert_eq!(ctr.values[1], 2_f64);
 	}
 
+	#[test]
+	fn focus_layout_test() {
+		let stats = RspamdStat::new(2, false);
+		let settings = PlotSettings { height: 6, ..Default::default() };
+		let layout = stats.plot_layout(&settings, 50);
+		assert_eq!(layout.len(), stats.selected_elements().len());
+		assert!(layout.iter().any(|(elt, _)| elt.kind == KnownCounter::Scanned));
+
+		let focused = PlotSettings { focus: Some(KnownCounter::Total), ..settings };
+		let layout = stats.plot_layout(&focused, 50);
+		assert_eq!(layout.len(), 1);
+		assert_eq!(layout[0].0.kind, KnownCounter::Total);
+		assert_eq!(layout[0].1.height, 47);
+	}
+
+	#[test]
+	fn string_numbers_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(2, false);
+		let json = r#"{"actions":{"reject":"1000","no action":2000},"scan_times":["0.5","0.25"]}"#;
+		stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).unwrap();
+		let json = r#"{"actions":{"reject":"1500","no action":2100},"scan_times":["0.5","0.25","inf","NaN"]}"#;
+		stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).unwrap();
+		assert_eq!(stats.spam_stats.values[0], 500_f64);
+		assert_eq!(stats.ham_stats.values[0], 100_f64);
+		assert_eq!(stats.avg_time.values[0], 0.375_f64);
+	}
+
+	#[test]
+	fn markers_test() {
+		let mut ctr = RspamdStatElement::new(4, KnownCounter::Unknown, true);
+		let elapsed = Duration::from_millis(1);
+		for v in [1_f64, 2_f64, 3_f64, 4_f64] {
+			ctr.update(v, elapsed).unwrap();
+		}
+		ctr.add_marker();
+		assert_eq!(ctr.marker_positions(), vec![2]);
+		assert_eq!(ctr.values[2], 3_f64);
+
+		for v in [5_f64, 6_f64] {
+			ctr.update(v, elapsed).unwrap();
+		}
+		assert_eq!(ctr.marker_positions(), vec![1]);
+		assert_eq!(ctr.values[1], 3_f64);
+
+		for v in [7_f64, 8_f64] {
+			ctr.update(v, elapsed).unwrap();
+		}
+		assert!(ctr.marker_positions().is_empty());
+		assert!(ctr.markers.is_empty());
+	}
+
+	#[test]
+	fn mark_rendered_test() {
+		let mut stats = RspamdStat::new(2, false);
+		stats.spam_stats.values.push_back(1_f64);
+		stats.ham_stats.values.push_back(2_f64);
+		stats.mark_rendered(&[KnownCounter::Spam]);
+		assert_eq!(stats.spam_stats.last_rendered, Some(1_f64));
+		assert_eq!(stats.ham_stats.last_rendered, None);
+	}
+
+	#[test]
+	fn window_acceptance_rate_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		assert!(window_acceptance_rate(&stats).is_nan());
+
+		for json in [
+			r#"{"actions":{"reject":0,"no action":0,"add header":0}}"#,
+			r#"{"actions":{"reject":10,"no action":30,"add header":0}}"#,
+			r#"{"actions":{"reject":20,"no action":80,"add header":10}}"#,
+		] {
+			stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).unwrap();
+		}
+
+		assert!((window_acceptance_rate(&stats) - 80.0 / 110.0).abs() < 1e-9);
+		assert!((window_rejection_rate(&stats) - 20.0 / 110.0).abs() < 1e-9);
+	}
+
+	#[test]
+	fn scan_times_numbers_test() {
+		let json = serde_json::json!([0.5, "0.25", null, 1]);
+		assert_eq!(extract_scan_times(&json), vec![0.5_f64, 0.25_f64, 1_f64]);
+		assert!(extract_scan_times(&serde_json::json!({})).is_empty());
+	}
+
+	#[test]
+	fn scan_times_objects_test() {
+		let json = serde_json::json!([
+			{"time": 0.5, "action": "reject"},
+			{"time": "0.25", "action": "no action"},
+			{"action": "greylist"},
+		]);
+		assert_eq!(extract_scan_times(&json), vec![0.5_f64, 0.25_f64]);
+
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(2, false);
+		for _ in 0..2 {
+			let doc = serde_json::json!({ "actions": {}, "scan_times": json.clone() });
+			stats.update_from_json(doc, elapsed).unwrap();
+		}
+		assert_eq!(stats.avg_time.values[0], 0.375_f64);
+	}
+
+	#[test]
+	fn lenient_json_test() {
+		let body = br#"{"actions":{"reject":1,"no action":"NaN"},"scan_times":[0.5,NaN,-Infinity,0.25]}"#;
+		let json = parse_stat_json(body).unwrap();
+		assert_eq!(extract_scan_times(&json["scan_times"]), vec![0.5_f64, 0.25_f64]);
+		assert_eq!(json["actions"]["no action"], "NaN");
+		assert!(extract_scan_times(&serde_json::json!(["inf", "-Infinity", {"time": "nan"}])).is_empty());
+
+		let mut stats = RspamdStat::new(2, false);
+		assert!(stats.update_from_json(json, Duration::from_secs(1)).is_ok());
+		assert!(parse_stat_json(b"<html>NaN</html>").is_err());
+	}
+
+	#[test]
+	fn instances_array_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(2, false);
+		let json = r#"[
+			{"actions":{"reject":100,"no action":1000},"scan_times":[0.5]},
+			{"actions":{"reject":200,"no action":"2000"},"scan_times":[0.25]}
+		]"#;
+		stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).unwrap();
+		let json = r#"[
+			{"actions":{"reject":110,"no action":1100},"scan_times":[0.5]},
+			{"actions":{"reject":205,"no action":"2050"},"scan_times":[0.25]}
+		]"#;
+		stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).unwrap();
+		assert_eq!(stats.spam_stats.values[0], 15_f64);
+		assert_eq!(stats.ham_stats.values[0], 150_f64);
+		assert_eq!(stats.avg_time.values[0], 0.375_f64);
+	}
+
+	#[test]
+	fn aggregate_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut instances = vec![RspamdStat::new(4, false); 2];
+		let mut stats = RspamdStat::new(4, false);
+		let mut tick = |samples: [Option<serde_json::Value>; 2], elapsed: Duration| {
+			for (instance, sample) in instances.iter_mut().zip(&samples) {
+				if let Some(json) = sample {
+					instance.ingest(json, elapsed).unwrap();
+				}
+			}
+			stats.aggregate(&instances, &samples);
+		};
+
+		tick(
+			[
+				Some(serde_json::json!({"actions": {"reject": 100, "no action": 1000}, "scan_times": [0.5]})),
+				Some(serde_json::json!({"actions": {"reject": 200, "no action": 2000}, "scan_times": [0.25]})),
+			],
+			elapsed,
+		);
+		tick(
+			[
+				Some(serde_json::json!({"actions": {"reject": 110, "no action": 1100}, "scan_times": [0.5]})),
+				Some(serde_json::json!({"actions": {"reject": 205, "no action": 2050}, "scan_times": [0.25]})),
+			],
+			elapsed,
+		);
+		// One instance going down keeps the window, only its share is missing
+		tick([Some(serde_json::json!({"actions": {"reject": 120, "no action": 1200}})), None], elapsed);
+		tick(
+			[
+				Some(serde_json::json!({"actions": {"reject": 130, "no action": 1300}})),
+				Some(serde_json::json!({"actions": {"reject": 215, "no action": 2150}})),
+			],
+			elapsed,
+		);
+		assert_eq!(stats.spam_stats.values, [15_f64, 10_f64, 20_f64]);
+		assert_eq!(stats.ham_stats.values, [150_f64, 100_f64, 200_f64]);
+		assert_eq!(stats.avg_time.values, [0.375_f64]);
+		assert_eq!(stats.scan_histogram.count, 2);
+	}
+
+	#[test]
+	fn paged_layout_test() {
+		let stats = RspamdStat::new(2, false);
+		let settings = PlotSettings { height: 6, charts_per_page: Some(3), page: 2, ..Default::default() };
+		let layout = stats.plot_layout(&settings, 50);
+		assert_eq!(layout.len(), 3);
+		assert_eq!(layout[0].0.kind, KnownCounter::ScanP95);
+	}
+
+	#[test]
+	fn soft_reject_test() {
+		let elapsed = Duration::from_secs(1);
+		let first = serde_json::json!({"actions": {"reject": 100, "soft reject": 50, "no action": 1000}});
+		let second = serde_json::json!({"actions": {"reject": 110, "soft reject": 70, "no action": 1100}});
+
+		let mut stats = RspamdStat::new(4, false);
+		stats.ingest(&first, elapsed).unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+		assert_eq!(stats.spam_stats.values, [10_f64]);
+
+		let mut stats = RspamdStat::new(4, false);
+		stats.spam_includes_soft_reject = true;
+		stats.ingest(&first, elapsed).unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+		assert_eq!(stats.spam_stats.values, [30_f64]);
+		assert_eq!(stats.total.values, [130_f64]);
+	}
+
+	#[test]
+	fn derived_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		stats.register_derived(|json, _| {
+			let reject = json["actions"]["reject"].as_f64()?;
+			let scanned = json["scanned"].as_f64().filter(|&scanned| scanned > 0.0)?;
+			Some(("reject_ratio".to_owned(), reject / scanned))
+		});
+		stats.register_derived(|_, stat| {
+			let spam = stat.spam_stats.values.back()?;
+			Some(("spam_per_min".to_owned(), spam * 60.0))
+		});
+
+		stats
+			.ingest(&serde_json::json!({"scanned": 0, "actions": {"reject": 0, "no action": 0}}), elapsed)
+			.unwrap();
+		assert!(stats.derived.is_empty());
+
+		stats
+			.ingest(&serde_json::json!({"scanned": 400, "actions": {"reject": 100, "no action": 300}}), elapsed)
+			.unwrap();
+		let ratio = stats.element(&KnownCounter::Derived("reject_ratio".into())).unwrap();
+		assert_eq!(ratio.values, [0.25_f64]);
+		assert_eq!(ratio.label(), "reject_ratio");
+		assert_eq!(stats.element(&KnownCounter::Derived("spam_per_min".into())).unwrap().values, [6000_f64]);
+		assert_eq!(stats.elements().len(), 16);
+
+		stats
+			.ingest(&serde_json::json!({"scanned": 1000, "actions": {"reject": 500, "no action": 500}}), elapsed)
+			.unwrap();
+		assert_eq!(stats.derived.len(), 2);
+		let meta = stats.counters_meta();
+		let ratio = meta.iter().find(|meta| meta.name == "reject_ratio").unwrap();
+		assert_eq!(ratio.window, [0.25_f64, 0.5_f64]);
+	}
+
+	#[test]
+	fn histogram_test() {
+		let mut histogram = HistogramCounter::with_bounds("scan_time", &[0.1, 0.5, 1.0]);
+		assert_eq!(histogram.cur_value(), [0_f64, 0.0, 0.0, 0.0]);
+
+		let buckets = histogram.update(vec![0.05, 0.1, 0.3, 0.7, 5.0, f64::NAN], 1000).unwrap();
+		assert_eq!(buckets, [2_f64, 3.0, 4.0, 5.0]);
+		assert_eq!(histogram.count, 5);
+		assert!((histogram.sum - 6.15).abs() < 1e-9);
+
+		let mut stats = RspamdStat::new(4, false);
+		let json = serde_json::json!({"actions": {}, "scan_times": [0.2, 0.3, 1.5]});
+		stats.ingest(&json, Duration::from_secs(1)).unwrap();
+		stats.ingest(&json, Duration::from_secs(1)).unwrap();
+		// The same ring buffer is not counted twice
+		assert_eq!(stats.scan_histogram.cur_value(), [0_f64, 1.0, 2.0, 2.0, 3.0, 3.0]);
+		let json = serde_json::json!({"actions": {}, "scan_times": [0.2, 0.05, 1.5]});
+		stats.ingest(&json, Duration::from_secs(1)).unwrap();
+		assert_eq!(stats.scan_histogram.cur_value(), [1_f64, 2.0, 3.0, 3.0, 4.0, 4.0]);
+		assert_eq!(stats.scan_histogram.bounds(), SCAN_TIME_BUCKETS);
+
+		histogram.reset();
+		assert_eq!(histogram.count, 0);
+		assert_eq!(histogram.cur_value(), [0_f64, 0.0, 0.0, 0.0]);
+	}
+
+	#[test]
+	fn counter_selection_test() {
+		let mut stats = RspamdStat::new(4, false);
+		stats.selection = Some("spam, ham,total".parse().unwrap());
+		let selected = stats.selected_elements().iter().map(|elt| elt.kind.clone()).collect::<Vec<_>>();
+		// Display order is kept
+		assert_eq!(selected, [KnownCounter::Spam, KnownCounter::Ham, KnownCounter::Total]);
+		assert_eq!(stats.collecting_progress(), Some((0, 3)));
+		assert_eq!(stats.plot_layout(&PlotSettings::default(), 50).len(), 3);
+
+		assert_eq!("spam,bogus".parse::<CounterSelection>(), Err("unknown counter: bogus".to_owned()));
+		assert!("".parse::<CounterSelection>().is_err());
+		stats.selection = None;
+		assert_eq!(stats.selected_elements().len(), stats.elements().len());
+	}
+
+	#[test]
+	fn total_source_test() {
+		let elapsed = Duration::from_secs(1);
+		let first = serde_json::json!({"scanned": 1200, "actions": {"reject": 100, "greylist": 50, "no action": 1000}});
+		let second =
+			serde_json::json!({"scanned": 1340, "actions": {"reject": 110, "greylist": 80, "no action": 1100}});
+
+		let mut stats = RspamdStat::new(4, false);
+		stats.ingest(&first, elapsed).unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+		assert_eq!(stats.total.values, [110_f64]);
+
+		let mut stats = RspamdStat::new(4, false);
+		stats.total_source = "scanned".parse().unwrap();
+		stats.ingest(&first, elapsed).unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+		assert_eq!(stats.total.values, [140_f64]);
+		// A stat without scanned still updates the other charts
+		let third = serde_json::json!({"actions": {"reject": 120, "greylist": 80, "no action": 1200}});
+		assert!(stats.ingest(&third, elapsed).is_ok());
+		assert_eq!(stats.spam_stats.values, [10_f64, 10_f64]);
+		// Switching between scanned and the action sum gives no bogus rate, only a new baseline
+		assert_eq!(stats.total.values, [140_f64]);
+		let fourth = serde_json::json!({"actions": {"reject": 130, "greylist": 80, "no action": 1300}});
+		stats.ingest(&fourth, elapsed).unwrap();
+		assert_eq!(stats.total.values, [140_f64, 110_f64]);
+		let fifth = serde_json::json!({"scanned": 1800, "actions": {"reject": 140, "greylist": 90, "no action": 1400}});
+		stats.ingest(&fifth, elapsed).unwrap();
+		assert_eq!(stats.total.values, [140_f64, 110_f64]);
+		let sixth =
+			serde_json::json!({"scanned": 1950, "actions": {"reject": 150, "greylist": 100, "no action": 1500}});
+		stats.ingest(&sixth, elapsed).unwrap();
+		assert_eq!(stats.total.values, [140_f64, 110_f64, 150_f64]);
+		assert!("other".parse::<TotalSource>().is_err());
+	}
+
+	#[test]
+	fn action_map_test() {
+		let elapsed = Duration::from_secs(1);
+		let first = serde_json::json!({"actions": {"reject": 100, "greylist": 50, "add header": 10, "rewrite subject": 5, "no action": 1000}});
+		let second = serde_json::json!({"actions": {"reject": 110, "greylist": 70, "add header": 30, "rewrite subject": 6, "no action": 1100}});
+
+		let overrides = ["greylist=spam", "add header = ignore"]
+			.iter()
+			.map(|s| s.parse::<ActionMapping>().unwrap())
+			.collect::<Vec<_>>();
+		let mut stats = RspamdStat::new(4, false);
+		stats.action_map = ActionMap::with_overrides(&overrides);
+		stats.ingest(&first, elapsed).unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+		assert_eq!(stats.spam_stats.values, [30_f64]);
+		assert_eq!(stats.junk_stats.values, [1_f64]);
+		assert_eq!(stats.ham_stats.values, [100_f64]);
+
+		assert_eq!(ActionMap::with_overrides(&[]), ActionMap::default());
+		assert_eq!(
+			"soft reject=ham".parse::<ActionMapping>(),
+			Ok(ActionMapping { action: "soft reject".to_owned(), category: ActionCategory::Ham })
+		);
+		assert!("greylist".parse::<ActionMapping>().is_err());
+		assert!("=spam".parse::<ActionMapping>().is_err());
+		assert!("greylist=other".parse::<ActionMapping>().is_err());
+	}
+
+	#[test]
+	fn normalize_action_key_test() {
+		assert_eq!(normalize_action_key("no_action"), normalize_action_key("no action"));
+		assert_eq!(normalize_action_key(" Add_Header "), "add header");
+		assert_eq!(KnownCounter::from("rewrite_subject"), KnownCounter::Junk);
+		assert_eq!(
+			"add_header=spam".parse::<ActionMapping>().unwrap(),
+			"add header=spam".parse::<ActionMapping>().unwrap()
+		);
+
+		// Older and newer spellings land in the same charts, even when mixed
+		let elapsed = Duration::from_secs(1);
+		let first = serde_json::json!({"actions": {"no_action": 100, "add_header": 10, "rewrite subject": 1}});
+		let second =
+			serde_json::json!({"actions": {"no action": 150, "add header": 15, "rewrite_subject": 1, "no_action": 10}});
+		let mut stats = RspamdStat::new(4, false);
+		stats.ingest(&first, elapsed).unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+		assert_eq!(stats.ham_stats.values, [60_f64]);
+		assert_eq!(stats.junk_stats.values, [5_f64]);
+
+		let mut map = ActionMap::default();
+		map.assign("no_action", ActionCategory::Ignore);
+		assert!(map.actions(ActionCategory::Ham).is_empty());
+	}
+
+	#[test]
+	fn num_elements_test() {
+		assert!(parse_num_elements("0").is_err());
+		assert!(parse_num_elements("1").is_err());
+		assert!(parse_num_elements("-5").is_err());
+		assert_eq!(parse_num_elements("2"), Ok(2));
+		assert_eq!(parse_num_elements("80"), Ok(80));
+	}
+
+	#[test]
+	fn correlation_test() {
+		let a = [1_f64, 2_f64, 3_f64, 4_f64, 5_f64];
+		let b = [2_f64, 4_f64, 5_f64, 4_f64, 5_f64];
+		assert!((correlation(&a, &b).unwrap() - 0.7745966692).abs() < 1e-9);
+		let inverse = [10_f64, 8_f64, 6_f64, 4_f64, 2_f64];
+		assert!((correlation(&a, &inverse).unwrap() + 1.0).abs() < 1e-9);
+
+		// Longer series are aligned by their tails
+		let longer = [100_f64, -50_f64, 1_f64, 2_f64, 3_f64, 4_f64, 5_f64];
+		assert!((correlation(&longer, &a).unwrap() - 1.0).abs() < 1e-9);
+
+		assert_eq!(correlation(&a, &[1_f64]), None);
+		assert_eq!(correlation(&a, &[3_f64; 5]), None);
+	}
+
+	#[test]
+	fn counters_meta_test() {
+		let mut stats = RspamdStat::new(4, false);
+		stats.total.values.extend([1_f64, 2_f64]);
+		let meta = stats.counters_meta();
+
+		let described = meta
+			.iter()
+			.map(|m| (m.name.as_str(), m.label.as_str(), m.unit))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			described,
+			vec![
+				("spam", "spam msg/sec", "msg/sec"),
+				("ham", "ham msg/sec", "msg/sec"),
+				("junk", "junk msg/sec", "msg/sec"),
+				("total", "total msg/sec", "msg/sec"),
+				("avg_time", "average_time sec", "sec"),
+				("scan_p50", "scan_time p50 sec", "sec"),
+				("scan_p95", "scan_time p95 sec", "sec"),
+				("scan_p99", "scan_time p99 sec", "sec"),
+				("scanned", "scanned msg/sec", "msg/sec"),
+				("learned", "learned msg/sec", "msg/sec"),
+				("connections", "connections conn/sec", "conn/sec"),
+				("ham_percentage", "ham percentage %", "%"),
+				("bytes_allocated", "bytes_allocated bytes", "bytes"),
+				("chunks_oversized", "chunks_oversized chunks/sec", "chunks/sec"),
+			]
+		);
+		assert_eq!(meta[3].current, Some(2_f64));
+		assert_eq!(meta[3].window, vec![1_f64, 2_f64]);
+		assert_eq!(meta[0].current, None);
+		assert_eq!(serde_json::to_value(&meta[3]).unwrap()["unit"], "msg/sec");
+	}
+
+	#[test]
+	fn ham_percentage_test() {
+		assert_eq!(ham_percentage(30.0, 120.0), Some(25.0));
+		assert_eq!(ham_percentage(0.0, 0.0), None);
+
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		let ticks = [
+			serde_json::json!({"actions": {"reject": 100, "no action": 1000}}),
+			serde_json::json!({"actions": {"reject": 190, "no action": 1030}}),
+			// No mail at all
+			serde_json::json!({"actions": {"reject": 190, "no action": 1030}}),
+		];
+		for tick in &ticks {
+			stats.ingest(tick, elapsed).unwrap();
+		}
+		assert_eq!(stats.ham_percentage.values, [25_f64, 25_f64]);
+
+		let mut idle = RspamdStat::new(4, false);
+		idle.ingest(&ticks[1], elapsed).unwrap();
+		idle.ingest(&ticks[2], elapsed).unwrap();
+		assert!(idle.ham_percentage.values.is_empty());
+	}
+
+	#[test]
+	fn percentile_test() {
+		let values = (1..=100).rev().map(f64::from).collect::<Vec<_>>();
+		assert_eq!(percentile(&values, 50.0), Some(50.5));
+		assert!((percentile(&values, 95.0).unwrap() - 95.05).abs() < 1e-9);
+		assert!((percentile(&values, 99.0).unwrap() - 99.01).abs() < 1e-9);
+
+		// Short series are interpolated, a single value is every percentile
+		assert!((percentile(&[0.1, 0.3], 50.0).unwrap() - 0.2).abs() < 1e-9);
+		assert_eq!(percentile(&[0.4], 99.0), Some(0.4));
+		assert_eq!(percentile(&[], 50.0), None);
+		assert_eq!(percentile(&[f64::NAN, 0.5, f64::INFINITY], 50.0), Some(0.5));
+
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		let json = serde_json::json!({"actions": {}, "scan_times": [0.1, 0.2, 0.3, 0.4, 2.0]});
+		stats.ingest(&json, elapsed).unwrap();
+		stats.ingest(&json, elapsed).unwrap();
+		assert_eq!(stats.scan_p50.values, [0.3]);
+		assert!((stats.scan_p99.values[0] - 1.936).abs() < 1e-9);
+	}
+
+	#[test]
+	fn session_summary_test() {
+		let elapsed = Duration::from_secs(1);
+		// The window is shorter than the session, the summary still covers all of it
+		let mut stats = RspamdStat::new(2, false);
+		for reject in [100, 110, 150, 160] {
+			stats
+				.ingest(&serde_json::json!({"actions": {"reject": reject}}), elapsed)
+				.unwrap();
+			stats.record_scrape(true);
+		}
+		stats.record_scrape(false);
+
+		let summary = serde_json::to_value(stats.session_summary(Duration::from_secs(5))).unwrap();
+		assert_eq!(summary["duration"], 5.0);
+		assert_eq!(summary["scrapes"], 5);
+		assert_eq!(summary["errors"], 1);
+		let spam = &summary["counters"][0];
+		assert_eq!(spam["name"], "spam");
+		assert_eq!(spam["min"], 10.0);
+		assert_eq!(spam["avg"], 20.0);
+		assert_eq!(spam["max"], 40.0);
+		assert_eq!(spam["last"], 10.0);
+		assert!(summary["counters"][4]["avg"].is_null());
+	}
+
+	#[test]
+	fn stddev_test() {
+		let values = [2_f64, 4_f64, 4_f64, 4_f64, 5_f64, 5_f64, 7_f64, 9_f64];
+		assert!((stddev(&values) - 2.0).abs() < 1e-9);
+		assert_eq!(stddev(&[3_f64]), 0.0);
+		assert!(stddev(&[]).is_nan());
+	}
+
+	#[test]
+	fn ingest_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		let first = serde_json::json!({"actions": {"reject": 100, "no action": 1000}, "scan_times": [0.5]});
+		let second = serde_json::json!({"actions": {"reject": 120, "no action": 1100}, "scan_times": [0.25]});
+		stats.ingest(&first, elapsed).unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+		assert_eq!(stats.spam_stats.values, [20_f64]);
+		assert_eq!(stats.ham_stats.values, [100_f64]);
+		assert_eq!(stats.total.values, [120_f64]);
+		assert_eq!(stats.avg_time.values, [0.5_f64]);
+		// Values are still usable by the caller
+		assert_eq!(first["actions"]["reject"], 100);
+	}
+
+	#[test]
+	fn collecting_progress_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		let total = stats.selected_elements().len();
+		assert_eq!(stats.collecting_progress(), Some((0, total)));
+
+		// The first sample only sets the baseline, except for allocated memory read as is
+		let json = r#"{"scanned":1100,"learned":0,"connections":10,"bytes_allocated":4096,"chunks_oversized":1,"actions":{"reject":100,"no action":1000},"scan_times":[0.5]}"#;
+		stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).unwrap();
+		assert_eq!(stats.collecting_progress(), Some((1, total)));
+
+		stats.spam_stats.update(105.0 * RATE_SCALE, elapsed).unwrap();
+		assert_eq!(stats.collecting_progress(), Some((2, total)));
+		let summary = stats.summary(&PlotSettings::default()).unwrap();
+		assert!(summary.contains(&format!("collecting… 2/{} samples", total)));
+
+		let json = r#"{"scanned":1210,"learned":0,"connections":12,"bytes_allocated":8192,"chunks_oversized":2,"actions":{"reject":110,"no action":1100},"scan_times":[0.5]}"#;
+		stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).unwrap();
+		assert_eq!(stats.collecting_progress(), None);
+		assert!(stats.summary(&PlotSettings::default()).is_none());
+
+		// Counters absent from the stat are not waited for
+		let mut stats = RspamdStat::new(4, false);
+		for scanned in [1100, 1210] {
+			let json = serde_json::json!({"scanned": scanned, "actions": {"reject": scanned / 10}});
+			stats.ingest(&json, elapsed).unwrap();
+		}
+		assert_eq!(stats.collecting_progress(), None);
+	}
+
+	#[test]
+	fn raw_counters_test() {
+		let elapsed = Duration::from_secs(2);
+		let mut stats = RspamdStat::new(4, false);
+		let json = r#"{"scanned":1000,"learned":0,"connections":100,"actions":{}}"#;
+		stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).unwrap();
+		let json = r#"{"scanned":1300,"learned":0,"connections":140,"actions":{}}"#;
+		stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).unwrap();
+
+		assert_eq!(stats.scanned_stats.values, [150_f64]);
+		assert_eq!(stats.connections_stats.values, [20_f64]);
+		// Read-only nodes never learn, but the chart is still drawn
+		assert_eq!(stats.learned_stats.values, [0_f64]);
+		assert_eq!(KnownCounter::from_name("connections"), Some(KnownCounter::Connections));
+	}
+
+	#[test]
+	fn memory_counters_test() {
+		let elapsed = Duration::from_secs(2);
+		let mut stats = RspamdStat::new(2, false);
+		for (bytes, oversized) in [(1000, 10), (1500, 30), (1200, 50)] {
+			let json = serde_json::json!({"bytes_allocated": bytes, "chunks_oversized": oversized, "actions": {}});
+			stats.ingest(&json, elapsed).unwrap();
+		}
+
+		// Allocated memory is a gauge showing the latest value, oversized chunks are a rate
+		assert_eq!(stats.bytes_allocated.values, [1500_f64, 1200_f64]);
+		assert_eq!(stats.chunks_oversized.values, [10_f64, 10_f64]);
+		assert_eq!(KnownCounter::from_name("bytes_allocated"), Some(KnownCounter::BytesAllocated));
+	}
+
 	#[test]
 	fn update_from_json() {
 		let json = r#"
 		{"version":"3.2","config_id":"8nm93w87h5zfhzxxtfqy3k7sb5afrfx7u77fdg7d984pd53hair54rwdgcfk9yizc9kebg8x5f6r5bfz3jjz4gmcgxb4kf4iyhnxmbn","uptime":60901,"read_only":false,"scanned":3216735051,"learned":0,"actions":{"reject":995165214,"soft reject":0,"rewrite subject":0,"add header":4187423843,"greylist":275270625,"no action":2053842666},"scan_times":[0.507925,0.209795,0.223006,0.647264,0.529891,0.273673,0.537307,0.533161,0.539620,0.535399,0.540692,0.227740,0.540794,0.254937,0.498498,0.220530,0.477884,0.555480,0.502577,0.499710,0.424071,0.485661,0.505764,0.492892,0.495350,0.260113,0.597570,0.588293,0.501595,0.519670,0.504542],"spam_count":5182589057,"ham_count":2329113291,"connections":18424244,"control_connections":881,"pools_allocated":18425045,"pools_freed":18425058,"bytes_allocated":1884077939,"chunks_allocated":2597,"shared_chunks_allocated":15,"chunks_freed":0,"chunks_oversized":7268949,"fragmented":0,"total_learns":0,"statfiles":[]}
 		"#;
 		let elapsed = Duration::from_secs(1);
-		let mut stats = RspamdStat::new(2);
+		let mut stats = RspamdStat::new(2, false);
 		assert!(stats.update_from_json(serde_json::from_str(json).unwrap(), elapsed).is_ok());
 	}
+
+	#[test]
+	fn raw_summary_test() {
+		let json = serde_json::json!({
+			"scanned": 1220,
+			"learned": "6",
+			"actions": {"reject": 120, "no action": 1100},
+			"uptime": 3600,
+			"version": "3.2"
+		});
+		assert_eq!(
+			raw_summary(&json),
+			"scanned 1220\nlearned 6\naction.no_action 1100\naction.reject 120\nuptime 3600"
+		);
+		assert_eq!(raw_summary(&serde_json::json!({"actions": {}})), "");
+	}
 }
@@ -0,0 +1,308 @@
+use crate::counters::RspamdStat;
+use chrono::{DateTime, FixedOffset, Local};
+use std::{
+	fs::{self, OpenOptions},
+	io::{self, BufWriter, Write},
+	path::{Path, PathBuf},
+	str::FromStr,
+	time::{Duration, Instant},
+};
+
+/// When to start a new CSV file, the full one is renamed after the time of its first row
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CsvRotation {
+	#[default]
+	None,
+	/// When the date of a row differs from the date of the first row in the file
+	Daily,
+	/// Before a row would take the file over this many bytes
+	Size(u64),
+}
+
+impl FromStr for CsvRotation {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"none" => Ok(CsvRotation::None),
+			"daily" => Ok(CsvRotation::Daily),
+			_ => s
+				.strip_prefix("size:")
+				.and_then(|bytes| bytes.parse().ok())
+				.filter(|&bytes| bytes > 0)
+				.map(CsvRotation::Size)
+				.ok_or_else(|| format!("invalid rotation: {}, expected none, daily or size:<bytes>", s)),
+		}
+	}
+}
+
+/// State of the file being rotated
+struct Rotation<W> {
+	policy: CsvRotation,
+	path: PathBuf,
+	open: fn(&Path) -> io::Result<W>,
+	/// Bytes in the current file
+	written: u64,
+	/// Time of the first row in the current file, used to name it once rotated
+	started: Option<DateTime<FixedOffset>>,
+}
+
+/// Writes the latest values of all counters as CSV rows
+pub struct CsvWriter<W: Write> {
+	out: W,
+	/// Written at the top of every rotated file
+	header: String,
+	/// Flush every row when unset
+	flush_interval: Option<Duration>,
+	last_flush: Instant,
+	rotation: Option<Rotation<W>>,
+}
+
+impl CsvWriter<Box<dyn Write + Send>> {
+	/// Opens a file for appending or stdout, the header is written unless the file already has data; only files are
+	/// rotated
+	pub fn open(
+		path: Option<&Path>,
+		stat: &RspamdStat,
+		rotation: CsvRotation,
+		flush_interval: Option<Duration>,
+	) -> io::Result<Self> {
+		match path {
+			Some(path) => {
+				let out = open_file(path)?;
+				let written = fs::metadata(path)?.len();
+				let mut writer = CsvWriter::new(out, stat, written == 0)?.with_flush_interval(flush_interval);
+				writer.rotation = Some(Rotation {
+					policy: rotation,
+					path: path.to_owned(),
+					open: open_file,
+					written: written.max(writer.header.len() as u64 + 1),
+					started: None,
+				});
+				Ok(writer)
+			},
+			None => Ok(CsvWriter::new(Box::new(BufWriter::new(io::stdout())) as Box<dyn Write + Send>, stat, true)?
+				.with_flush_interval(flush_interval)),
+		}
+	}
+}
+
+impl<W: Write> CsvWriter<W> {
+	pub fn new(out: W, stat: &RspamdStat, write_header: bool) -> io::Result<Self> {
+		let mut writer =
+			Self { out, header: csv_header(stat), flush_interval: None, last_flush: Instant::now(), rotation: None };
+
+		if write_header {
+			let header = writer.header.clone();
+			writer.write_line(&header)?;
+		}
+
+		Ok(writer)
+	}
+
+	/// Batches rows, flushing them at most once per `interval`
+	pub fn with_flush_interval(mut self, interval: Option<Duration>) -> Self {
+		self.flush_interval = interval;
+		self
+	}
+
+	/// Appends a row, starting a new file first when rotation is due; rows are flushed at once unless batched, so
+	/// `tail -f` shows live data
+	pub fn write_row(&mut self, stat: &RspamdStat, timestamp: &str) -> io::Result<()> {
+		let line = csv_row(stat, timestamp);
+		self.rotate(&line, timestamp)?;
+		self.write_line(&line)
+	}
+
+	pub fn into_inner(self) -> W {
+		self.out
+	}
+
+	fn rotate(&mut self, line: &str, timestamp: &str) -> io::Result<()> {
+		let header_len = self.header.len() as u64 + 1;
+		let rotation = match self.rotation.as_mut() {
+			Some(rotation) => rotation,
+			None => return Ok(()),
+		};
+		let time = DateTime::parse_from_rfc3339(timestamp).unwrap_or_else(|_| Local::now().fixed_offset());
+		let started = *rotation.started.get_or_insert(time);
+		let due = match rotation.policy {
+			CsvRotation::None => false,
+			CsvRotation::Daily => started.date_naive() != time.date_naive(),
+			// A file always gets at least one row, even one longer than the limit
+			CsvRotation::Size(max) => rotation.written > header_len && rotation.written + line.len() as u64 + 1 > max,
+		};
+		if !due {
+			return Ok(());
+		}
+
+		self.out.flush()?;
+		fs::rename(&rotation.path, rotated_path(&rotation.path, &started.format("%Y%m%dT%H%M%S").to_string()))?;
+		self.out = (rotation.open)(&rotation.path)?;
+		rotation.written = 0;
+		rotation.started = Some(time);
+		let header = self.header.clone();
+		self.write_line(&header)
+	}
+
+	fn write_line(&mut self, line: &str) -> io::Result<()> {
+		writeln!(self.out, "{}", line)?;
+		if let Some(rotation) = self.rotation.as_mut() {
+			rotation.written += line.len() as u64 + 1;
+		}
+		if self
+			.flush_interval
+			.map_or(true, |interval| self.last_flush.elapsed() >= interval)
+		{
+			self.out.flush()?;
+			self.last_flush = Instant::now();
+		}
+
+		Ok(())
+	}
+}
+
+fn open_file(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+	let file = OpenOptions::new().create(true).append(true).open(path)?;
+	Ok(Box::new(BufWriter::new(file)))
+}
+
+/// Returns a free name for a rotated file, e.g. `stats-20220301T100000.csv`
+fn rotated_path(path: &Path, stamp: &str) -> PathBuf {
+	let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+	let ext = path
+		.extension()
+		.map(|ext| format!(".{}", ext.to_string_lossy()))
+		.unwrap_or_default();
+	(0..)
+		.map(|n| match n {
+			0 => path.with_file_name(format!("{}-{}{}", stem, stamp, ext)),
+			n => path.with_file_name(format!("{}-{}-{}{}", stem, stamp, n, ext)),
+		})
+		.find(|candidate| !candidate.exists())
+		.unwrap()
+}
+
+/// Returns the header row: a timestamp followed by counter names
+pub fn csv_header(stat: &RspamdStat) -> String {
+	let mut fields = vec!["timestamp"];
+	fields.extend(stat.elements().into_iter().map(|elt| elt.kind.name()));
+
+	fields.join(",")
+}
+
+/// Returns a row with the latest value of each counter, counters without values are left empty
+pub fn csv_row(stat: &RspamdStat, timestamp: &str) -> String {
+	let mut fields = vec![timestamp.to_owned()];
+	fields.extend(
+		stat.elements()
+			.into_iter()
+			.map(|elt| elt.values.back().map(|v| v.to_string()).unwrap_or_default()),
+	);
+
+	fields.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		counters::RspamdStat,
+		csv::{CsvRotation, CsvWriter},
+	};
+	use std::{fs, io::BufWriter, time::Duration};
+
+	#[test]
+	fn csv_writer_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		let mut writer = CsvWriter::new(Vec::new(), &stats, true).unwrap();
+		stats
+			.ingest(&serde_json::json!({"actions": {"reject": 100, "no action": 1000}}), elapsed)
+			.unwrap();
+		stats
+			.ingest(&serde_json::json!({"actions": {"reject": 105, "no action": 1015}}), elapsed)
+			.unwrap();
+		writer.write_row(&stats, "2022-03-01T10:00:00+00:00").unwrap();
+
+		let out = String::from_utf8(writer.into_inner()).unwrap();
+		let lines = out.lines().collect::<Vec<_>>();
+		assert_eq!(lines.len(), 2);
+		assert!(lines[0].starts_with("timestamp,spam,ham,junk,total,avg_time,"));
+		assert!(lines[1].starts_with("2022-03-01T10:00:00+00:00,5,15,0,20,,"));
+		assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+
+		let writer = CsvWriter::new(Vec::new(), &stats, false).unwrap();
+		assert!(writer.into_inner().is_empty());
+	}
+
+	#[test]
+	fn flush_interval_test() {
+		let stats = RspamdStat::new(4, false);
+		let mut writer = CsvWriter::new(BufWriter::new(Vec::new()), &stats, true)
+			.unwrap()
+			.with_flush_interval(Some(Duration::from_secs(3600)));
+		writer.write_row(&stats, "2022-03-01T10:00:00+00:00").unwrap();
+		// Only the header, flushed before batching was enabled, has reached the output
+		assert_eq!(writer.out.get_ref().iter().filter(|&&b| b == b'\n').count(), 1);
+		assert_eq!(
+			writer
+				.into_inner()
+				.into_inner()
+				.unwrap()
+				.iter()
+				.filter(|&&b| b == b'\n')
+				.count(),
+			2
+		);
+	}
+
+	#[test]
+	fn rotation_test() {
+		assert_eq!("none".parse(), Ok(CsvRotation::None));
+		assert_eq!("daily".parse(), Ok(CsvRotation::Daily));
+		assert_eq!("size:1000".parse(), Ok(CsvRotation::Size(1000)));
+		for invalid in ["size:0", "size:", "1000", "weekly"] {
+			assert!(invalid.parse::<CsvRotation>().is_err(), "{}", invalid);
+		}
+
+		let dir = std::env::temp_dir().join(format!("rspamd-mon-csv-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("stats.csv");
+		let stats = RspamdStat::new(4, false);
+		let header_len = super::csv_header(&stats).len() as u64 + 1;
+		let row_len = super::csv_row(&stats, "2022-03-01T10:00:00+00:00").len() as u64 + 1;
+
+		// Room for the header and two rows
+		let rotation = CsvRotation::Size(header_len + 2 * row_len);
+		let mut writer = CsvWriter::open(Some(&path), &stats, rotation, None).unwrap();
+		for time in ["10:00:00", "10:00:01", "10:00:02"] {
+			writer.write_row(&stats, &format!("2022-03-01T{}+00:00", time)).unwrap();
+		}
+		drop(writer);
+
+		let rotated = fs::read_to_string(dir.join("stats-20220301T100000.csv")).unwrap();
+		assert_eq!(rotated.lines().count(), 3);
+		let current = fs::read_to_string(&path).unwrap();
+		let lines = current.lines().collect::<Vec<_>>();
+		assert_eq!(lines.len(), 2);
+		assert!(lines[0].starts_with("timestamp,"));
+		assert!(lines[1].starts_with("2022-03-01T10:00:02+00:00,"));
+
+		// Daily rotation starts a new file with the first row of the next day
+		let mut writer = CsvWriter::open(Some(&path), &stats, CsvRotation::Daily, None).unwrap();
+		writer.write_row(&stats, "2022-03-01T23:59:59+00:00").unwrap();
+		writer.write_row(&stats, "2022-03-02T00:00:00+00:00").unwrap();
+		drop(writer);
+		assert_eq!(
+			fs::read_to_string(dir.join("stats-20220301T235959.csv"))
+				.unwrap()
+				.lines()
+				.count(),
+			3
+		);
+		assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,140 @@
+use crate::{client::AuthError, counters::RspamdStat};
+use std::fmt;
+
+/// Relative difference between scanned and action rates tolerated by `consistency_check`
+pub const CONSISTENCY_TOLERANCE: f64 = 0.1;
+
+/// Rates (in msg/sec) below this difference are never reported, e.g. on idle servers
+const MIN_RATE_DIFFERENCE: f64 = 1.0;
+
+/// Suspicious state of the polled stats
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Warning {
+	pub scanned_rate: f64,
+	pub actions_rate: f64,
+}
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"scanned grows by {:.2} msg/sec, but actions by {:.2} msg/sec: stats may be stale or come from a wrong source",
+			self.scanned_rate, self.actions_rate
+		)
+	}
+}
+
+/// Checks that the number of scanned messages grows as fast as the number of actions taken on them
+///
+/// Each scanned message gets an action, so diverging rates mean the stats are stale (e.g. cached by a proxy)
+/// or assembled from different sources.
+pub fn consistency_check(stat: &RspamdStat) -> Option<Warning> {
+	stat.scanned?;
+	let scanned_rate = *stat.scanned_stats.values.back()?;
+	let actions_rate = stat.actions_rate?;
+	let difference = (scanned_rate - actions_rate).abs();
+	let tolerance = (scanned_rate.max(actions_rate) * CONSISTENCY_TOLERANCE).max(MIN_RATE_DIFFERENCE);
+
+	(difference > tolerance).then_some(Warning { scanned_rate, actions_rate })
+}
+
+/// Outcome of `--once` as a monitoring plugin exit status, following the Nagios convention
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitStatus {
+	Ok,
+	/// Rspamd rejected the password, which will not fix itself
+	Critical,
+	/// Rspamd could not be polled, it may be fine on the next check
+	Unknown,
+}
+
+impl ExitStatus {
+	/// Classifies the result of polling
+	pub fn of(res: &color_eyre::Result<()>) -> Self {
+		match res {
+			Ok(()) => ExitStatus::Ok,
+			Err(e) if e.downcast_ref::<AuthError>().is_some() => ExitStatus::Critical,
+			Err(_) => ExitStatus::Unknown,
+		}
+	}
+
+	pub fn to_code(self) -> i32 {
+		match self {
+			ExitStatus::Ok => 0,
+			ExitStatus::Critical => 2,
+			ExitStatus::Unknown => 3,
+		}
+	}
+}
+
+impl fmt::Display for ExitStatus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			ExitStatus::Ok => "OK",
+			ExitStatus::Critical => "CRITICAL",
+			ExitStatus::Unknown => "UNKNOWN",
+		})
+	}
+}
+
+/// Returns the status line printed by `--once`, e.g. `UNKNOWN: cannot poll localhost: ...`
+pub fn status_line(res: &color_eyre::Result<()>) -> String {
+	match res {
+		Ok(()) => ExitStatus::Ok.to_string(),
+		Err(e) => format!("{}: {}", ExitStatus::of(res), e),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		client::AuthError,
+		counters::RspamdStat,
+		diagnostics::{consistency_check, status_line, ExitStatus},
+	};
+	use color_eyre::eyre::eyre;
+	use reqwest::StatusCode;
+	use std::time::Duration;
+
+	#[test]
+	fn consistency_check_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		assert!(consistency_check(&stats).is_none());
+
+		let json = serde_json::json!({"scanned": 1000, "actions": {"reject": 100, "greylist": 100, "no action": 800}});
+		stats.ingest(&json, elapsed).unwrap();
+		assert!(consistency_check(&stats).is_none());
+
+		// Greylisted messages are not charted, but they are still counted
+		let json = serde_json::json!({"scanned": 1100, "actions": {"reject": 110, "greylist": 150, "no action": 840}});
+		stats.ingest(&json, elapsed).unwrap();
+		assert!(consistency_check(&stats).is_none());
+
+		let json = serde_json::json!({"scanned": 1300, "actions": {"reject": 110, "greylist": 150, "no action": 840}});
+		stats.ingest(&json, elapsed).unwrap();
+		let warning = consistency_check(&stats).unwrap();
+		assert_eq!(warning.scanned_rate, 200.0);
+		assert_eq!(warning.actions_rate, 0.0);
+		assert!(warning.to_string().contains("stale"));
+
+		stats.reset_counters();
+		assert!(consistency_check(&stats).is_none());
+	}
+
+	#[test]
+	fn exit_status_test() {
+		let ok: color_eyre::Result<()> = Ok(());
+		let auth: color_eyre::Result<()> =
+			Err(AuthError { name: "localhost".to_owned(), status: StatusCode::FORBIDDEN }.into());
+		let fetch = Err(eyre!("cannot poll localhost: connection refused"));
+
+		assert_eq!([&ok, &auth, &fetch].map(|res| ExitStatus::of(res).to_code()), [0, 2, 3]);
+		assert_eq!(status_line(&ok), "OK");
+		assert_eq!(
+			status_line(&auth),
+			"CRITICAL: authentication required or wrong password for localhost: 403 Forbidden"
+		);
+		assert_eq!(status_line(&fetch), "UNKNOWN: cannot poll localhost: connection refused");
+	}
+}
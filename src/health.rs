@@ -0,0 +1,113 @@
+use crate::counters::{window_rejection_rate, RspamdStat};
+use std::str::FromStr;
+
+/// Weights of the health score components
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HealthWeights {
+	/// Weight of the spam ratio
+	pub spam: f64,
+	/// Weight of the scan time
+	pub latency: f64,
+	/// Weight of the scrape error rate
+	pub errors: f64,
+	/// Scan time (in seconds) considered as bad as it gets
+	pub latency_limit: f64,
+}
+
+impl Default for HealthWeights {
+	fn default() -> Self {
+		Self { spam: 0.4, latency: 0.4, errors: 0.2, latency_limit: 2.0 }
+	}
+}
+
+impl FromStr for HealthWeights {
+	type Err = String;
+
+	/// Parses weights in form of `spam,latency,errors`, weights are non-negative and not all zero
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let weights = s
+			.split(',')
+			.map(|w| match w.trim().parse::<f64>() {
+				Ok(weight) if weight.is_finite() && weight >= 0.0 => Ok(weight),
+				Ok(_) => Err(format!("invalid weight {}: expected a non-negative number", w)),
+				Err(e) => Err(format!("invalid weight {}: {}", w, e)),
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		match weights.as_slice() {
+			// The score is normalized by the sum, so zero weights would leave nothing to score
+			[spam, latency, errors] if spam + latency + errors > 0.0 => {
+				Ok(Self { spam: *spam, latency: *latency, errors: *errors, ..Default::default() })
+			},
+			[_, _, _] => Err(format!("at least one weight must be positive, got: {}", s)),
+			_ => Err(format!("expected three weights (spam,latency,errors), got: {}", s)),
+		}
+	}
+}
+
+/// Computes a composite health score from 0 (worst) to 100 (best) using spam ratio over
+/// the window, the latest scan time and the recent scrape error rate
+pub fn health_score(stat: &RspamdStat, weights: &HealthWeights) -> f64 {
+	let spam = unit(window_rejection_rate(stat));
+	let latency = unit(stat.avg_time.values.back().map_or(0.0, |t| t / weights.latency_limit));
+	let errors = unit(stat.error_rate());
+	let total = weights.spam + weights.latency + weights.errors;
+
+	if total > 0.0 {
+		let penalty = weights.spam * spam + weights.latency * latency + weights.errors * errors;
+		100.0 * (1.0 - penalty / total)
+	} else {
+		100.0
+	}
+}
+
+/// Clamps a value to `[0, 1]`, treating unknown values as zero
+fn unit(v: f64) -> f64 {
+	if v.is_nan() {
+		0.0
+	} else {
+		v.clamp(0.0, 1.0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		counters::RspamdStat,
+		health::{health_score, HealthWeights},
+	};
+
+	fn stat_with(spam: f64, total: f64, scan_time: f64) -> RspamdStat {
+		let mut stat = RspamdStat::new(4, false);
+		stat.spam_stats.values.push_back(spam);
+		stat.total.values.push_back(total);
+		stat.avg_time.values.push_back(scan_time);
+		stat
+	}
+
+	#[test]
+	fn health_score_test() {
+		let weights = HealthWeights::default();
+		assert_eq!(health_score(&RspamdStat::new(4, false), &weights), 100.0);
+
+		let healthy = health_score(&stat_with(1.0, 10.0, 0.1), &weights);
+		let more_spam = health_score(&stat_with(5.0, 10.0, 0.1), &weights);
+		let slower = health_score(&stat_with(5.0, 10.0, 1.5), &weights);
+		assert!(healthy > more_spam);
+		assert!(more_spam > slower);
+
+		let mut failing = stat_with(5.0, 10.0, 1.5);
+		failing.record_scrape(true);
+		failing.record_scrape(false);
+		assert!(health_score(&failing, &weights) < slower);
+
+		assert!((health_score(&stat_with(10.0, 10.0, 4.0), &weights) - 20.0).abs() < 1e-9);
+		assert_eq!("0.5,0.5,0".parse::<HealthWeights>().unwrap().errors, 0.0);
+		assert!("0.5,0.5".parse::<HealthWeights>().is_err());
+		// The score would leave the 0-100 range
+		assert!("2,-1,0".parse::<HealthWeights>().is_err());
+		assert!("nan,1,1".parse::<HealthWeights>().is_err());
+		assert!("inf,1,1".parse::<HealthWeights>().is_err());
+		assert!("0,0,0".parse::<HealthWeights>().is_err());
+	}
+}
@@ -0,0 +1,75 @@
+use crate::counters::RspamdStat;
+use std::io::{self, Write};
+
+/// Writes newline-delimited JSON objects with the latest values of all counters
+pub struct JsonWriter<W: Write> {
+	out: W,
+}
+
+impl<W: Write> JsonWriter<W> {
+	pub fn new(out: W) -> Self {
+		Self { out }
+	}
+
+	/// Writes an object for the current poll, flushing it at once, so it could be piped into `jq`
+	pub fn write_object(&mut self, stat: &RspamdStat, timestamp: &str) -> io::Result<()> {
+		writeln!(self.out, "{}", json_object(stat, timestamp))?;
+		self.out.flush()
+	}
+
+	pub fn into_inner(self) -> W {
+		self.out
+	}
+}
+
+/// Returns an object with the timestamp, the latest value of each counter and the health score, missing values are
+/// `null`
+pub fn json_object(stat: &RspamdStat, timestamp: &str) -> serde_json::Value {
+	let mut object = serde_json::Map::new();
+	object.insert("timestamp".to_owned(), timestamp.into());
+
+	for elt in stat.elements() {
+		let value = elt.values.back().cloned().filter(|value| value.is_finite());
+		object.insert(elt.kind.name().to_owned(), value.map_or(serde_json::Value::Null, Into::into));
+	}
+	object.insert("health_score".to_owned(), stat.health().map_or(serde_json::Value::Null, Into::into));
+
+	serde_json::Value::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{counters::RspamdStat, json::JsonWriter};
+	use std::time::Duration;
+
+	#[test]
+	fn json_writer_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		let mut writer = JsonWriter::new(Vec::new());
+		let first = serde_json::json!({"actions": {"reject": 100, "no action": 1000}, "scan_times": [0.5]});
+		let second = serde_json::json!({"actions": {"reject": 105, "no action": 1015}, "scan_times": [0.25]});
+		stats.ingest(&first, elapsed).unwrap();
+		writer.write_object(&stats, "2022-03-01T10:00:00+00:00").unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+		writer.write_object(&stats, "2022-03-01T10:00:01+00:00").unwrap();
+
+		let out = String::from_utf8(writer.into_inner()).unwrap();
+		let objects = out
+			.lines()
+			.map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(objects.len(), 2);
+		// No rates are known before the second poll
+		assert!(objects[0]["spam"].is_null());
+		assert!(objects[0]["avg_time"].is_null());
+		assert!(objects[0]["health_score"].is_null());
+		assert_eq!(objects[1]["timestamp"], "2022-03-01T10:00:01+00:00");
+		assert_eq!(objects[1]["spam"], 5.0);
+		assert_eq!(objects[1]["total"], 20.0);
+		assert_eq!(objects[1]["avg_time"], 0.5);
+		assert!(objects[1]["health_score"]
+			.as_f64()
+			.is_some_and(|score| (0.0..=100.0).contains(&score)));
+	}
+}
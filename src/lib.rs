@@ -1,2 +1,15 @@
+pub mod cli;
+pub mod client;
 pub mod counters;
+pub mod csv;
+pub mod diagnostics;
+pub mod health;
+pub mod json;
+pub mod logging;
 pub mod plot;
+pub mod poll;
+pub mod prometheus;
+pub mod pushgateway;
+pub mod replay;
+pub mod shared;
+pub mod statsd;
@@ -0,0 +1,138 @@
+use env_logger::{fmt::TimestampPrecision, Builder, Target};
+use log::LevelFilter;
+use std::{
+	fs::OpenOptions,
+	io,
+	path::Path,
+	time::{Duration, Instant},
+};
+
+/// Returns where log messages are written: charts are drawn to stdout, so logs never go there
+pub fn log_target(log_file: Option<&Path>) -> io::Result<Target> {
+	match log_file {
+		Some(path) => {
+			let file = OpenOptions::new().create(true).append(true).open(path)?;
+			Ok(Target::Pipe(Box::new(file)))
+		},
+		None => Ok(Target::Stderr),
+	}
+}
+
+/// Configures the logger for a verbosity level: -v - info, -vv - debug, -vvv - trace
+pub fn logger(verbose: i8, log_file: Option<&Path>) -> io::Result<Builder> {
+	let level = match verbose {
+		0 => LevelFilter::Warn,
+		1 => LevelFilter::Info,
+		2 => LevelFilter::Debug,
+		_ => LevelFilter::Trace,
+	};
+	let mut builder = Builder::from_default_env();
+	builder
+		.filter(None, level)
+		.format_timestamp(Some(TimestampPrecision::Micros))
+		.target(log_target(log_file)?);
+
+	Ok(builder)
+}
+
+/// Throttles logging of a repeated error, so a long outage does not flood logs
+#[derive(Clone, Debug)]
+pub struct ErrorThrottle {
+	interval: Duration,
+	/// The last logged error and when it was logged
+	last: Option<(String, Instant)>,
+	/// Occurrences of the last error since it was logged
+	repeats: u64,
+}
+
+impl ErrorThrottle {
+	pub fn new(interval: Duration) -> Self {
+		Self { interval, last: None, repeats: 0 }
+	}
+
+	/// Returns a line to log for an error, `None` if the same error has been logged recently
+	pub fn check(&mut self, error: &str, now: Instant) -> Option<String> {
+		match &self.last {
+			Some((last, logged)) if last == error => {
+				self.repeats += 1;
+
+				if now.duration_since(*logged) < self.interval {
+					return None;
+				}
+
+				let line = format!("still failing ({} times): {}", self.repeats, error);
+				self.last = Some((error.to_owned(), now));
+				self.repeats = 0;
+				Some(line)
+			},
+			_ => {
+				self.last = Some((error.to_owned(), now));
+				self.repeats = 0;
+				Some(error.to_owned())
+			},
+		}
+	}
+
+	/// Forgets the last error after a successful scrape
+	pub fn reset(&mut self) {
+		self.last = None;
+		self.repeats = 0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::logging::{log_target, logger, ErrorThrottle};
+	use env_logger::Target;
+	use log::{Level, Log, Metadata, Record};
+	use std::time::{Duration, Instant};
+
+	#[test]
+	fn log_target_test() {
+		assert!(matches!(log_target(None).unwrap(), Target::Stderr));
+
+		let path = std::env::temp_dir().join(format!("rspamd-mon-{}.log", std::process::id()));
+		assert!(matches!(log_target(Some(&path)).unwrap(), Target::Pipe(_)));
+		assert!(path.exists());
+		let _ = std::fs::remove_file(path);
+	}
+
+	#[test]
+	fn logger_test() {
+		// The logger of the binary writes where `log_target` points, the file here and stderr by default, so logs
+		// never mix with charts on stdout
+		let path = std::env::temp_dir().join(format!("rspamd-mon-{}-logger.log", std::process::id()));
+		let file_logger = logger(0, Some(&path)).unwrap().build();
+		file_logger.log(&Record::builder().args(format_args!("cannot poll")).level(Level::Warn).build());
+		file_logger.log(&Record::builder().args(format_args!("connected")).level(Level::Info).build());
+		file_logger.flush();
+
+		let logged = std::fs::read_to_string(&path).unwrap();
+		let _ = std::fs::remove_file(path);
+		assert!(logged.contains("cannot poll"));
+		assert!(!logged.contains("connected"));
+		let info = Metadata::builder().level(Level::Info).build();
+		assert!(logger(1, None).unwrap().build().enabled(&info));
+	}
+
+	#[test]
+	fn error_throttle_test() {
+		let start = Instant::now();
+		let mut throttle = ErrorThrottle::new(Duration::from_secs(60));
+		let logged = (0..10)
+			.filter_map(|i| throttle.check("connection refused", start + Duration::from_secs(i)))
+			.collect::<Vec<_>>();
+		assert_eq!(logged, vec!["connection refused".to_owned()]);
+
+		// Another error is logged at once
+		assert_eq!(throttle.check("timeout", start + Duration::from_secs(10)).as_deref(), Some("timeout"));
+		assert!(throttle.check("timeout", start + Duration::from_secs(20)).is_none());
+		assert_eq!(
+			throttle.check("timeout", start + Duration::from_secs(70)).as_deref(),
+			Some("still failing (2 times): timeout")
+		);
+
+		throttle.reset();
+		assert!(throttle.check("timeout", start + Duration::from_secs(71)).is_some());
+	}
+}
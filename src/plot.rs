@@ -1,47 +1,844 @@
-use crate::counters::RspamdStatElement;
+use crate::counters::{stddev, KnownCounter, RspamdStatElement, MIN_ELEMENTS};
 use crossterm::{
 	cursor,
-	terminal::{Clear, ClearType},
+	terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
 	QueueableCommand,
 };
-use owo_colors::OwoColorize;
+use owo_colors::{AnsiColors, OwoColorize};
 use rasciigraph::{plot, Config};
-use std::io::{stdout, Write};
+use std::{
+	collections::VecDeque,
+	io::{stdout, Write},
+	ops::Range,
+	str::FromStr,
+};
+
+/// Settings used to render each chart
+#[derive(Clone, Debug, Default)]
+pub struct PlotSettings {
+	/// Chart height
+	pub height: u16,
+	/// Plot `ln(1 + x)` instead of raw values, so small and large magnitudes are both visible
+	pub log_scale: bool,
+	/// Draw only this counter using the whole terminal
+	pub focus: Option<KnownCounter>,
+	/// Show acceptance and rejection rates over the whole window below the charts
+	pub window_summary: bool,
+	/// Round plotted values to this number of decimals to smooth the chart
+	pub round: Option<u32>,
+	/// Draw chart lines using a per-counter color
+	pub line_colors: bool,
+	/// Maximum number of charts shown at once
+	pub charts_per_page: Option<usize>,
+	/// Page to show when charts do not fit on one page
+	pub page: usize,
+	/// Show health score below the charts
+	pub health: bool,
+	/// Show correlation of these counters below the charts
+	pub correlate: Option<(KnownCounter, KnownCounter)>,
+	/// Optional fields added to chart captions
+	pub caption_fields: CaptionFields,
+	/// Show a short single-line caption
+	pub compact_caption: bool,
+	/// Plot total as the running mean over the window instead of the per-tick sum
+	pub smooth_total: bool,
+	/// Plot the moving average over this number of values instead of raw values
+	pub smooth: Option<usize>,
+	/// Plot at most this number of the latest values, e.g. to fit the terminal; the history is kept anyway
+	pub width: Option<usize>,
+	/// Draw a reference line at this scan time (in seconds) on the average scan time chart
+	pub slo_scan_time: Option<f64>,
+	/// Render charts and captions as plain text, without ANSI colors
+	pub no_color: bool,
+}
+
+/// When to use colors
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorChoice {
+	/// Only when stdout is a terminal and `NO_COLOR` is not set
+	#[default]
+	Auto,
+	Always,
+	Never,
+}
+
+impl FromStr for ColorChoice {
+	type Err = String;
 
-/// Draws a specific graph using CLI graphs
-pub fn show_specific_counter(elt: &RspamdStatElement, row: u16, max_height: u16) -> u16 {
-	if elt.values.is_empty() {
-		return row;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"auto" => Ok(ColorChoice::Auto),
+			"always" => Ok(ColorChoice::Always),
+			"never" => Ok(ColorChoice::Never),
+			_ => Err(format!("unknown color choice: {}, expected auto, always or never", s)),
+		}
 	}
+}
 
-	let _ = stdout().queue(cursor::MoveTo(0, row * (max_height + 3)));
-	let sliced_values: Vec<f64> = elt.values.iter().cloned().collect();
-	let avg = sliced_values.iter().sum::<f64>() / sliced_values.len() as f64;
-	let min = *sliced_values.iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap_or(&0_f64);
-	let max = *sliced_values.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap_or(&0_f64);
-	let last = *sliced_values.last().unwrap_or(&0.0);
-	let plot_config = Config::default()
-		.with_height(max_height as u32)
-		.with_width(elt.nelts() as u32)
-		.with_caption(format!(
-			"[Label: {}] [LAST: {}] [AVG: {}] [MIN: {}] [MAX: {}]",
-			elt.counter.label().to_string().bold(),
-			format!("{:.2}", last).bright_purple().underline(),
-			format!("{:.2}", avg).white().bold(),
-			format!("{:.2}", min).green().bold(),
-			format!("{:.2}", max).red().bold(),
-		));
-	let _ = stdout().write(plot(sliced_values, plot_config).as_bytes());
+impl ColorChoice {
+	/// Whether colors are used, given whether `NO_COLOR` is set to a non-empty value and stdout is a terminal
+	pub fn enabled(self, no_color_env: bool, is_terminal: bool) -> bool {
+		match self {
+			ColorChoice::Auto => !no_color_env && is_terminal,
+			ColorChoice::Always => true,
+			ColorChoice::Never => false,
+		}
+	}
+}
+
+/// Columns taken by the axis labels of a chart
+const CHART_LABEL_COLUMNS: u16 = 14;
+
+/// Returns the number of values that fit into a chart drawn in a terminal with `cols` columns
+pub fn fit_chart_width(cols: u16) -> usize {
+	(cols.saturating_sub(CHART_LABEL_COLUMNS) as usize).max(MIN_ELEMENTS)
+}
+
+/// Terminals narrower than this get compact captions automatically
+pub const COMPACT_CAPTION_COLUMNS: u16 = 100;
+
+/// Optional caption fields
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CaptionFields {
+	/// Standard deviation of the displayed values
+	pub stddev: bool,
+	/// Raw count change in the last tick, next to the rate
+	pub delta: bool,
+	/// Raw count since the start
+	pub since_start: bool,
+}
+
+impl FromStr for CaptionFields {
+	type Err = String;
+
+	/// Parses a comma separated list of fields, e.g. `sd,delta,since_start`
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut fields = Self::default();
+
+		for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+			match field {
+				"sd" | "stddev" => fields.stddev = true,
+				"delta" => fields.delta = true,
+				"since_start" => fields.since_start = true,
+				_ => return Err(format!("unknown caption field: {}", field)),
+			}
+		}
+
+		Ok(fields)
+	}
+}
+
+impl PlotSettings {
+	/// Whether a summary line is shown below the charts
+	pub fn has_summary(&self) -> bool {
+		self.window_summary || self.health || self.correlate.is_some()
+	}
+}
+
+/// Draws a rendered chart at a specific row
+pub fn show_specific_counter(chart: &str, row: u16, settings: &PlotSettings) -> u16 {
+	let _ = stdout().queue(cursor::MoveTo(0, row * (settings.height + 3)));
+	let _ = stdout().write(chart.as_bytes());
 
 	row + 1
 }
 
+/// Returns chart height which lets `charts` charts (each taking `height + 3` rows) fit into the terminal,
+/// reducing the height if needed; returns None if even one-row charts do not fit
+pub fn fit_chart_height(charts: usize, height: u16, term_rows: u16) -> Option<u16> {
+	let per_chart = term_rows as usize / charts.max(1);
+
+	if per_chart >= height as usize + 3 {
+		Some(height)
+	} else if per_chart >= 4 {
+		Some((per_chart - 3) as u16)
+	} else {
+		None
+	}
+}
+
+/// Returns the range of items shown on a specific page, pages wrap around after the last one
+pub fn page_range(total: usize, per_page: usize, page: usize) -> Range<usize> {
+	let per_page = per_page.max(1);
+	let pages = total.div_ceil(per_page).max(1);
+	let start = (page % pages) * per_page;
+
+	start.min(total)..(start + per_page).min(total)
+}
+
+/// Draws a line of text below the charts
+pub fn show_summary(text: &str, row: u16, settings: &PlotSettings) {
+	let _ = stdout().queue(cursor::MoveTo(0, row * (settings.height + 3)));
+	let _ = stdout().write_all(text.as_bytes());
+}
+
+/// Renders a chart for a specific counter as a string, each chart is as wide as its data
+pub fn render_counter(elt: &RspamdStatElement, settings: &PlotSettings) -> String {
+	let chart = render_chart(elt, settings);
+
+	if settings.no_color {
+		strip_ansi(&chart)
+	} else {
+		chart
+	}
+}
+
+fn render_chart(elt: &RspamdStatElement, settings: &PlotSettings) -> String {
+	let series = plot_series(elt, settings);
+	// rasciigraph cannot scale infinite values and panics on an empty series, so only the caption is shown
+	let series = match finite_series(&series) {
+		Some(series) => series,
+		None => return format!("\n{}", counter_caption(elt, settings)),
+	};
+	// Positions of markers within the plotted tail of the values
+	let skipped = elt.values.len() - series.len();
+	let markers = elt
+		.marker_positions()
+		.into_iter()
+		.filter_map(|pos| pos.checked_sub(skipped))
+		.collect::<Vec<_>>();
+	let plot_config = Config::default()
+		.with_height(settings.height as u32)
+		.with_width(series.len() as u32)
+		.with_caption(counter_caption(elt, settings));
+	let slo = settings.slo_scan_time.filter(|_| elt.kind == KnownCounter::AvgTime).map(|slo| {
+		if settings.log_scale {
+			slo.max(0.0).ln_1p()
+		} else {
+			slo
+		}
+	});
+	let slo_row = slo.and_then(|slo| threshold_row(&series, settings.height, slo));
+	let chart = plot(series.clone(), plot_config);
+	let chart = if settings.log_scale { unlog_axis_labels(&chart, &series) } else { chart };
+	let chart = match slo_row {
+		Some(row) => overlay_threshold(&chart, row),
+		None => chart,
+	};
+	let chart = mark_chart(&chart, &markers);
+
+	match line_color(&elt.kind).filter(|_| settings.line_colors) {
+		Some(color) => colorize_chart(&chart, color),
+		None => chart,
+	}
+}
+
+/// Replaces axis labels of a chart plotted in log scale with the values they stand for; labels are computed from
+/// the plotted series like rasciigraph does, as its rounded labels would be off after exponentiation
+fn unlog_axis_labels(chart: &str, series: &[f64]) -> String {
+	let (body, caption) = match chart.rsplit_once('\n') {
+		Some(parts) => parts,
+		None => return chart.to_owned(),
+	};
+	let min = series.iter().cloned().fold(f64::INFINITY, f64::min);
+	let max = series.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+	let rows = body.lines().count().saturating_sub(1).max(1) as f64;
+	let lines = body
+		.lines()
+		.enumerate()
+		.map(|(i, line)| match line.find(['┤', '┼']) {
+			Some(axis) => {
+				let magnitude = max - i as f64 * (max - min) / rows;
+				(format!("{:.2}", magnitude.exp_m1().max(0.0)), &line[axis..])
+			},
+			None => (String::new(), line),
+		})
+		.collect::<Vec<_>>();
+	let width = lines.iter().map(|(label, _)| label.len()).max().unwrap_or(0) + 1;
+	let body = lines
+		.into_iter()
+		.map(|(label, rest)| format!("{:>width$} {}", label, rest, width = width))
+		.collect::<Vec<_>>();
+
+	format!("{}\n{}", body.join("\n"), caption)
+}
+
+/// Replaces non-finite values with the previous finite one (or the first finite one at the start),
+/// returns None if there are no finite values
+fn finite_series(series: &[f64]) -> Option<Vec<f64>> {
+	let first = series.iter().cloned().find(|v| v.is_finite())?;
+	let mut last = first;
+
+	Some(
+		series
+			.iter()
+			.map(|&v| {
+				if v.is_finite() {
+					last = v;
+				}
+				last
+			})
+			.collect(),
+	)
+}
+
+/// Pads charts on the left, so the latest values of all charts end up in the same column
+pub fn align_charts(charts: Vec<String>) -> Vec<String> {
+	let edges = charts.iter().map(|chart| data_right_edge(chart)).collect::<Vec<_>>();
+	let common = edges.iter().cloned().max().unwrap_or(0);
+
+	charts
+		.into_iter()
+		.zip(edges)
+		.map(|(chart, edge)| {
+			let padding = " ".repeat(common - edge);
+			map_chart_body(&chart, |line| format!("{}{}", padding, line))
+		})
+		.collect()
+}
+
+/// Returns the column right after the latest plotted value
+pub fn data_right_edge(chart: &str) -> usize {
+	let body = chart.rsplit_once('\n').map_or(chart, |(body, _)| body);
+	body.lines().map(visible_width).max().unwrap_or(0)
+}
+
+/// Removes ANSI escape sequences
+fn strip_ansi(text: &str) -> String {
+	let mut plain = String::with_capacity(text.len());
+	let mut chars = text.chars();
+
+	while let Some(c) = chars.next() {
+		if c == '\x1b' {
+			// Skip the whole escape sequence which ends with a letter
+			for c in chars.by_ref() {
+				if c.is_ascii_alphabetic() {
+					break;
+				}
+			}
+			continue;
+		}
+		plain.push(c);
+	}
+
+	plain
+}
+
+/// Returns the width of a line up to its last visible non-space character, ignoring ANSI escapes
+fn visible_width(line: &str) -> usize {
+	strip_ansi(line).trim_end_matches(' ').chars().count()
+}
+
+/// Applies a function to every chart line, leaving the caption (which is always the last line) intact
+fn map_chart_body(chart: &str, f: impl Fn(&str) -> String) -> String {
+	match chart.rsplit_once('\n') {
+		Some((body, caption)) => {
+			let body = body.lines().map(f).collect::<Vec<_>>();
+			format!("{}\n{}", body.join("\n"), caption)
+		},
+		None => chart.to_owned(),
+	}
+}
+
+/// Draws event markers as vertical lines over empty chart cells
+fn mark_chart(chart: &str, markers: &[usize]) -> String {
+	if markers.is_empty() {
+		return chart.to_owned();
+	}
+
+	map_chart_body(chart, |line| {
+		let mut chars = line.chars().collect::<Vec<_>>();
+		if let Some(axis) = chars.iter().position(|&c| c == '┤' || c == '┼') {
+			// The very first value is drawn on the axis itself
+			for &pos in markers.iter().filter(|&&pos| pos > 0) {
+				if let Some(c) = chars.get_mut(axis + pos).filter(|c| **c == ' ') {
+					*c = '┊';
+				}
+			}
+		}
+		chars.into_iter().collect()
+	})
+}
+
+/// Returns the chart row (counting from the top) where `threshold` would be plotted, using the same scaling as
+/// rasciigraph; returns None if the threshold is outside of the plotted range
+pub fn threshold_row(series: &[f64], height: u16, threshold: f64) -> Option<usize> {
+	let min = series.iter().cloned().fold(f64::INFINITY, f64::min);
+	let max = series.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+	let interval = max - min;
+	let ratio = if interval != 0.0 { height as f64 / interval } else { 1.0 };
+	let min2 = (min * ratio).round();
+	let rows = (max * ratio).round() - min2;
+	let y = (threshold * ratio).round() - min2;
+
+	(0.0..=rows).contains(&y).then_some((rows - y) as usize)
+}
+
+/// Draws a horizontal reference line over empty cells of a chart row
+fn overlay_threshold(chart: &str, row: usize) -> String {
+	let (body, caption) = chart.rsplit_once('\n').unwrap_or((chart, ""));
+	let body = body
+		.lines()
+		.enumerate()
+		.map(|(i, line)| {
+			let mut chars = line.chars().collect::<Vec<_>>();
+			if let Some(axis) = chars.iter().position(|&c| c == '┤' || c == '┼').filter(|_| i == row) {
+				chars.iter_mut().skip(axis + 1).filter(|c| **c == ' ').for_each(|c| *c = '╌');
+			}
+			chars.into_iter().collect::<String>()
+		})
+		.collect::<Vec<_>>();
+
+	format!("{}\n{}", body.join("\n"), caption)
+}
+
+/// Color used to draw chart lines of a specific counter
+pub fn line_color(counter: &KnownCounter) -> Option<AnsiColors> {
+	match counter {
+		KnownCounter::Spam => Some(AnsiColors::Red),
+		KnownCounter::Ham => Some(AnsiColors::Green),
+		KnownCounter::Junk => Some(AnsiColors::Yellow),
+		KnownCounter::Total => Some(AnsiColors::Cyan),
+		KnownCounter::AvgTime => Some(AnsiColors::Magenta),
+		KnownCounter::ScanP50 => Some(AnsiColors::BrightWhite),
+		KnownCounter::ScanP95 => Some(AnsiColors::BrightYellow),
+		KnownCounter::ScanP99 => Some(AnsiColors::BrightRed),
+		KnownCounter::Scanned => Some(AnsiColors::Blue),
+		KnownCounter::Learned => Some(AnsiColors::BrightMagenta),
+		KnownCounter::Connections => Some(AnsiColors::BrightCyan),
+		KnownCounter::HamPercentage => Some(AnsiColors::BrightGreen),
+		KnownCounter::BytesAllocated => Some(AnsiColors::White),
+		KnownCounter::ChunksOversized => Some(AnsiColors::BrightBlue),
+		KnownCounter::Derived(_) | KnownCounter::Unknown => None,
+	}
+}
+
+/// Colors chart lines, leaving the caption intact
+fn colorize_chart(chart: &str, color: AnsiColors) -> String {
+	map_chart_body(chart, |line| line.color(color).to_string())
+}
+
+/// Returns values to be plotted, transformed according to the settings
+pub fn plot_series(elt: &RspamdStatElement, settings: &PlotSettings) -> Vec<f64> {
+	let values = if settings.smooth_total && elt.kind == KnownCounter::Total {
+		running_mean(&elt.values)
+	} else if let Some(points) = settings.smooth {
+		elt.smoothed(points)
+	} else {
+		elt.values.iter().cloned().collect()
+	};
+	let values = values.into_iter();
+	let values: Vec<f64> = if settings.log_scale {
+		// Rates are not negative, but clamp them anyway to keep the logarithm finite
+		values.map(|v| v.max(0.0).ln_1p()).collect()
+	} else {
+		values.collect()
+	};
+
+	let values = match settings.round {
+		Some(decimals) => {
+			let scale = 10_f64.powi(decimals as i32);
+			values.into_iter().map(|v| (v * scale).round() / scale).collect()
+		},
+		None => values,
+	};
+
+	match settings.width {
+		Some(width) if width < values.len() => values[values.len() - width..].to_vec(),
+		_ => values,
+	}
+}
+
+/// Returns mean of all values up to each position, so the last one is the mean of the whole window
+pub fn running_mean(values: &VecDeque<f64>) -> Vec<f64> {
+	let mut sum = 0_f64;
+
+	values
+		.iter()
+		.enumerate()
+		.map(|(i, v)| {
+			sum += v;
+			sum / (i + 1) as f64
+		})
+		.collect()
+}
+
+/// Builds a caption for a counter, always using untransformed values
+pub fn counter_caption(elt: &RspamdStatElement, settings: &PlotSettings) -> String {
+	let values = &elt.values;
+	let finite = values.iter().cloned().filter(|v| v.is_finite());
+	let count = finite.clone().count();
+	let avg = if count == 0 { 0.0 } else { finite.clone().sum::<f64>() / count as f64 };
+	let min = finite.clone().reduce(f64::min).unwrap_or(0.0);
+	let max = finite.reduce(f64::max).unwrap_or(0.0);
+	let last = *values.back().unwrap_or(&0.0);
+
+	if settings.compact_caption {
+		return format!("{}: {:.2} ({:.2}–{:.2})", elt.label().bold(), last, min, max);
+	}
+
+	let mut caption = format!(
+		"[Label: {}] [LAST: {}{}] [AVG: {}] [MIN: {}] [MAX: {}]",
+		elt.label().to_string().bold(),
+		format!("{:.2}", last).bright_purple().underline(),
+		last_delta(elt, last),
+		format!("{:.2}", avg).white().bold(),
+		format!("{:.2}", min).green().bold(),
+		format!("{:.2}", max).red().bold(),
+	);
+
+	if settings.caption_fields.stddev {
+		let values = values.iter().cloned().collect::<Vec<_>>();
+		caption.push_str(&format!(" [SD: {}]", format!("{:.2}", stddev(&values)).cyan()));
+	}
+
+	if let Some(delta) = elt.last_delta().filter(|_| settings.caption_fields.delta) {
+		caption.push_str(&format!(" [DELTA: {}]", format!("{:+}", delta).cyan()));
+	}
+
+	if let Some(total) = elt.since_start.filter(|_| settings.caption_fields.since_start) {
+		caption.push_str(&format!(" [SINCE START: {}]", format!("{}", total).cyan()));
+	}
+
+	caption
+}
+
+/// Annotates the last value with its change since the previously displayed frame
+fn last_delta(elt: &RspamdStatElement, last: f64) -> String {
+	match elt.last_rendered {
+		Some(prev) => {
+			let delta = format!(" ({:+.2})", last - prev);
+			if last > prev {
+				delta.bright_green().to_string()
+			} else if last < prev {
+				delta.bright_red().to_string()
+			} else {
+				delta.dimmed().to_string()
+			}
+		},
+		None => String::new(),
+	}
+}
+
+/// Returns the number of terminal rows if stdout is a terminal
+pub fn term_rows() -> Option<u16> {
+	terminal::size().ok().map(|(_, rows)| rows)
+}
+
+/// Returns the number of terminal columns if stdout is a terminal
+pub fn term_cols() -> Option<u16> {
+	terminal::size().ok().map(|(cols, _)| cols)
+}
+
+/// Switches to the alternate screen, so the scrollback is left intact, and hides the cursor
+pub fn enter_term() {
+	let _ = stdout().queue(EnterAlternateScreen).and_then(|out| out.queue(cursor::Hide));
+	let _ = stdout().flush();
+}
+
+/// Undoes `enter_term`, called on exit
+pub fn restore_term() {
+	let _ = stdout().queue(cursor::Show).and_then(|out| out.queue(LeaveAlternateScreen));
+	let _ = stdout().flush();
+}
+
 /// Prepare terminal to show graphs
 pub fn prepare_term() {
-	let _ = stdout().queue(Clear(ClearType::All)).unwrap();
+	let _ = stdout().queue(Clear(ClearType::All));
 }
 
 /// Flush graphs to stdout
 pub fn finalise_term() {
 	let _ = stdout().flush();
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		counters::{KnownCounter, RspamdStat, RspamdStatElement, RATE_SCALE},
+		plot::{
+			align_charts, counter_caption, data_right_edge, finite_series, fit_chart_height, fit_chart_width,
+			line_color, page_range, plot_series, render_counter, running_mean, strip_ansi, threshold_row,
+			visible_width, CaptionFields, ColorChoice, PlotSettings,
+		},
+	};
+	use std::time::Duration;
+
+	#[test]
+	fn log_scale_test() {
+		let mut elt = RspamdStatElement::new(4, KnownCounter::Unknown, true);
+		elt.values.extend([0_f64, 9_f64, 99_f64, 999_f64]);
+		let settings = PlotSettings { height: 6, log_scale: true, ..Default::default() };
+
+		let series = plot_series(&elt, &settings);
+		let expected = [0_f64, 10_f64.ln(), 100_f64.ln(), 1000_f64.ln()];
+		assert!(series.iter().zip(expected.iter()).all(|(a, b)| (a - b).abs() < 1e-9));
+		let linear = plot_series(&elt, &PlotSettings { log_scale: false, ..settings.clone() });
+		assert_eq!(linear, vec![0_f64, 9_f64, 99_f64, 999_f64]);
+
+		let caption = counter_caption(&elt, &settings);
+		assert!(caption.contains("999.00"));
+		assert!(caption.contains("0.00"));
+		assert!(caption.contains("276.75"));
+
+		// Axis labels show real values, not logarithms
+		let chart = render_counter(&elt, &settings);
+		let labels = chart
+			.lines()
+			.filter_map(|line| line.split_once('┤').or_else(|| line.split_once('┼')))
+			.map(|(label, _)| label.trim())
+			.collect::<Vec<_>>();
+		assert_eq!(labels.first(), Some(&"999.00"));
+		assert_eq!(labels.last(), Some(&"0.00"));
+		assert!(!labels.contains(&"6.91"));
+	}
+
+	#[test]
+	fn round_test() {
+		let mut elt = RspamdStatElement::new(4, KnownCounter::Unknown, true);
+		elt.values.extend([1.234_f64, 5.678_f64, 2.001_f64]);
+		let settings = PlotSettings { height: 6, round: Some(1), ..Default::default() };
+
+		assert_eq!(plot_series(&elt, &settings), vec![1.2_f64, 5.7_f64, 2.0_f64]);
+		let caption = counter_caption(&elt, &settings);
+		assert!(caption.contains("2.00"));
+		assert!(caption.contains("2.97"));
+		assert!(caption.contains("1.23"));
+		assert!(caption.contains("5.68"));
+	}
+
+	#[test]
+	fn line_colors_test() {
+		let settings = PlotSettings { height: 2, line_colors: true, ..Default::default() };
+
+		for (counter, code) in [
+			(KnownCounter::Spam, "\x1b[31m"),
+			(KnownCounter::Ham, "\x1b[32m"),
+			(KnownCounter::Junk, "\x1b[33m"),
+			(KnownCounter::Total, "\x1b[36m"),
+			(KnownCounter::AvgTime, "\x1b[35m"),
+		] {
+			assert!(line_color(&counter).is_some());
+			let mut elt = RspamdStatElement::new(4, counter, true);
+			elt.values.extend([1_f64, 2_f64, 3_f64]);
+			let chart = render_counter(&elt, &settings);
+			let (body, caption) = chart.rsplit_once('\n').unwrap();
+			assert!(body.lines().all(|line| line.starts_with(code)));
+			assert!(!caption.starts_with(code));
+			let plain = render_counter(&elt, &PlotSettings { line_colors: false, ..settings.clone() });
+			assert!(plain.lines().all(|line| !line.starts_with(code)));
+			let no_color = render_counter(&elt, &PlotSettings { no_color: true, ..settings.clone() });
+			assert!(!no_color.contains('\x1b'));
+			assert_eq!(no_color, strip_ansi(&chart));
+			assert!(no_color.contains("[Label: "));
+		}
+	}
+
+	#[test]
+	fn color_choice_test() {
+		assert_eq!("never".parse::<ColorChoice>().unwrap(), ColorChoice::Never);
+		assert!("sometimes".parse::<ColorChoice>().is_err());
+		assert!(ColorChoice::Auto.enabled(false, true));
+		// Piped output and NO_COLOR disable colors unless they are forced
+		assert!(!ColorChoice::Auto.enabled(false, false));
+		assert!(!ColorChoice::Auto.enabled(true, true));
+		assert!(ColorChoice::Always.enabled(true, false));
+		assert!(!ColorChoice::Never.enabled(false, true));
+	}
+
+	#[test]
+	fn fit_chart_height_test() {
+		assert_eq!(fit_chart_height(5, 6, 50), Some(6));
+		assert_eq!(fit_chart_height(5, 6, 45), Some(6));
+		assert_eq!(fit_chart_height(5, 6, 30), Some(3));
+		assert_eq!(fit_chart_height(5, 6, 19), None);
+		assert_eq!(fit_chart_height(1, 20, 10), Some(7));
+	}
+
+	#[test]
+	fn page_range_test() {
+		assert_eq!(page_range(5, 2, 0), 0..2);
+		assert_eq!(page_range(5, 2, 1), 2..4);
+		assert_eq!(page_range(5, 2, 2), 4..5);
+		assert_eq!(page_range(5, 2, 3), 0..2);
+		assert_eq!(page_range(5, 10, 1), 0..5);
+		assert_eq!(page_range(0, 2, 1), 0..0);
+	}
+
+	#[test]
+	fn align_charts_test() {
+		let settings = PlotSettings { height: 2, line_colors: true, ..Default::default() };
+		let mut full = RspamdStatElement::new(4, KnownCounter::Spam, true);
+		full.values.extend([1_f64, 2_f64, 3_f64, 4_f64]);
+		let mut partial = RspamdStatElement::new(4, KnownCounter::Ham, true);
+		partial.values.extend([3_f64, 4_f64]);
+
+		let unaligned = render_counter(&partial, &settings);
+		let charts = align_charts(vec![render_counter(&full, &settings), unaligned.clone()]);
+		assert_eq!(data_right_edge(&charts[0]), data_right_edge(&charts[1]));
+		assert_eq!(data_right_edge(&charts[1]), data_right_edge(&unaligned) + 2);
+		assert_eq!(charts[1].rsplit_once('\n').unwrap().1, unaligned.rsplit_once('\n').unwrap().1);
+	}
+
+	#[test]
+	fn last_delta_test() {
+		let mut elt = RspamdStatElement::new(4, KnownCounter::Unknown, true);
+		elt.values.push_back(12.3_f64);
+		let settings = PlotSettings::default();
+		assert!(!counter_caption(&elt, &settings).contains('('));
+
+		elt.last_rendered = elt.values.back().cloned();
+		elt.values.push_back(12.7_f64);
+		assert!(counter_caption(&elt, &settings).contains("(+0.40)"));
+
+		elt.last_rendered = elt.values.back().cloned();
+		elt.values.push_back(12.2_f64);
+		assert!(counter_caption(&elt, &settings).contains("(-0.50)"));
+	}
+
+	#[test]
+	fn caption_fields_test() {
+		let mut elt = RspamdStatElement::new(4, KnownCounter::Unknown, true);
+		elt.values.extend([2_f64, 4_f64, 4_f64, 6_f64]);
+		assert!(!counter_caption(&elt, &PlotSettings::default()).contains("[SD:"));
+
+		let caption_fields = "sd".parse::<CaptionFields>().unwrap();
+		let settings = PlotSettings { caption_fields, ..Default::default() };
+		assert!(counter_caption(&elt, &settings).contains("1.41"));
+		assert!("sd,bogus".parse::<CaptionFields>().is_err());
+
+		let caption_fields = "delta".parse::<CaptionFields>().unwrap();
+		let settings = PlotSettings { caption_fields, ..Default::default() };
+		let mut elt = RspamdStatElement::new(4, KnownCounter::Spam, false);
+		elt.update(100_f64 * RATE_SCALE, Duration::from_secs(1)).unwrap();
+		assert!(!counter_caption(&elt, &settings).contains("[DELTA:"));
+		elt.update(137_f64 * RATE_SCALE, Duration::from_secs(2)).unwrap();
+		let caption = counter_caption(&elt, &settings);
+		assert!(caption.contains("18.50") && caption.contains("+37"));
+		assert!(!counter_caption(&elt, &PlotSettings::default()).contains("[DELTA:"));
+
+		let caption_fields = "since_start".parse::<CaptionFields>().unwrap();
+		let settings = PlotSettings { caption_fields, ..Default::default() };
+		elt.update(150_f64 * RATE_SCALE, Duration::from_secs(1)).unwrap();
+		assert!(strip_ansi(&counter_caption(&elt, &settings)).contains("[SINCE START: 50]"));
+		assert!(!counter_caption(&RspamdStatElement::new(4, KnownCounter::AvgTime, true), &settings).contains("SINCE"));
+	}
+
+	#[test]
+	fn compact_caption_test() {
+		let mut elt = RspamdStatElement::new(4, KnownCounter::AvgTime, true);
+		elt.values.extend([0.25_f64, 123456.789_f64, 99999.99_f64]);
+		elt.last_rendered = Some(1.0);
+		let settings = PlotSettings { compact_caption: true, ..Default::default() };
+
+		let caption = counter_caption(&elt, &settings);
+		assert_eq!(strip_ansi(&caption), "average_time sec: 99999.99 (0.25–123456.79)");
+		assert!(visible_width(&caption) < visible_width(&counter_caption(&elt, &PlotSettings::default())));
+
+		// Fits a classic 80 columns terminal for every counter, up to a gigabyte of allocated memory
+		let stat = RspamdStat::new(4, false);
+		for elt in stat.elements() {
+			for magnitude in [0.001_f64, 12.5_f64, 123456.789_f64, 1e9_f64] {
+				let mut elt = elt.clone();
+				elt.values.extend([magnitude / 2.0, magnitude, magnitude / 3.0]);
+				elt.last_rendered = Some(magnitude / 4.0);
+				let caption = counter_caption(&elt, &settings);
+				assert!(visible_width(&caption) <= 80, "{} is too wide", strip_ansi(&caption));
+			}
+		}
+	}
+
+	#[test]
+	fn single_value_test() {
+		let mut elt = RspamdStatElement::new(4, KnownCounter::Total, false);
+		elt.values.push_back(42_f64);
+		let chart = render_counter(&elt, &PlotSettings { height: 6, ..Default::default() });
+
+		assert!(chart.lines().last().unwrap().contains("[LAST: "));
+		assert!(chart.lines().count() > 1);
+	}
+
+	#[test]
+	fn chart_width_test() {
+		let mut elt = RspamdStatElement::new(8, KnownCounter::Spam, false);
+		for v in [1_f64, 2_f64, 3_f64, 4_f64, 5_f64, 6_f64] {
+			elt.push(v);
+		}
+		elt.add_marker();
+		let settings = PlotSettings { height: 4, width: Some(3), ..Default::default() };
+
+		assert_eq!(plot_series(&elt, &settings), vec![4_f64, 5_f64, 6_f64]);
+		// A narrow chart does not truncate the history
+		assert_eq!(elt.values.len(), 6);
+		assert_eq!(plot_series(&elt, &PlotSettings { width: Some(10), ..settings.clone() }).len(), 6);
+		let narrow = render_counter(&elt, &settings);
+		let wide = render_counter(&elt, &PlotSettings { width: None, ..settings });
+		assert!(data_right_edge(&narrow) < data_right_edge(&wide));
+
+		assert_eq!(fit_chart_width(94), 80);
+		assert_eq!(fit_chart_width(10), 2);
+	}
+
+	#[test]
+	fn smooth_total_test() {
+		let mut elt = RspamdStatElement::new(4, KnownCounter::Total, false);
+		elt.values.extend([10_f64, 20_f64, 60_f64, 30_f64]);
+		let settings = PlotSettings { smooth_total: true, ..Default::default() };
+
+		let series = plot_series(&elt, &settings);
+		assert_eq!(series, vec![10_f64, 15_f64, 30_f64, 30_f64]);
+		let mean = elt.values.iter().sum::<f64>() / elt.values.len() as f64;
+		assert_eq!(*series.last().unwrap(), mean);
+		assert_eq!(running_mean(&elt.values), series);
+		// The caption still shows the instantaneous value
+		assert!(counter_caption(&elt, &settings).contains("30.00"));
+
+		// Other counters are plotted as is
+		elt.kind = KnownCounter::Spam;
+		assert_eq!(plot_series(&elt, &settings), vec![10_f64, 20_f64, 60_f64, 30_f64]);
+
+		let settings = PlotSettings { smooth: Some(2), ..Default::default() };
+		assert_eq!(plot_series(&elt, &settings), vec![10_f64, 15_f64, 40_f64, 45_f64]);
+		// Captions keep raw values
+		let caption = counter_caption(&elt, &settings);
+		assert!(caption.contains("30.00") && !caption.contains("45.00"));
+		assert_eq!(elt.values, [10_f64, 20_f64, 60_f64, 30_f64]);
+	}
+
+	#[test]
+	fn slo_line_test() {
+		let mut elt = RspamdStatElement::new(4, KnownCounter::AvgTime, true);
+		elt.values.extend([0_f64, 1_f64, 2_f64, 3_f64]);
+		let settings = PlotSettings { height: 6, slo_scan_time: Some(2.0), ..Default::default() };
+
+		// Rows go from 3.0 at the top to 0.0 at the bottom, 0.5 each
+		assert_eq!(threshold_row(&plot_series(&elt, &settings), 6, 2.0), Some(2));
+		assert_eq!(threshold_row(&[0_f64, 3_f64], 6, 3.5), None);
+
+		let chart = render_counter(&elt, &settings);
+		let slo_lines = chart
+			.lines()
+			.enumerate()
+			.filter(|(_, line)| line.contains('╌'))
+			.collect::<Vec<_>>();
+		assert_eq!(slo_lines.len(), 1);
+		assert_eq!(slo_lines[0].0, 2);
+		assert!(slo_lines[0].1.trim_start().starts_with("2.00"));
+
+		// Out of the plotted range and on other charts no line is drawn
+		let above = PlotSettings { slo_scan_time: Some(10.0), ..settings.clone() };
+		assert!(!render_counter(&elt, &above).contains('╌'));
+		elt.kind = KnownCounter::Total;
+		assert!(!render_counter(&elt, &settings).contains('╌'));
+	}
+
+	#[test]
+	fn adversarial_values_test() {
+		let settings = PlotSettings { height: 4, ..Default::default() };
+		let mut elt = RspamdStatElement::new(4, KnownCounter::AvgTime, true);
+		// Nothing to plot, but the caption is still there
+		assert!(render_counter(&elt, &settings).contains("[LAST: "));
+
+		elt.values.extend([f64::NAN, 1_f64, f64::INFINITY, 3_f64]);
+		assert_eq!(finite_series(&plot_series(&elt, &settings)).unwrap(), vec![1_f64, 1_f64, 1_f64, 3_f64]);
+		let chart = render_counter(&elt, &settings);
+		assert!(chart.lines().count() > 1);
+		let caption = counter_caption(&elt, &settings);
+		assert!(caption.contains("[MIN: ") && caption.contains("1.00") && caption.contains("3.00"));
+		assert!(strip_ansi(&caption).contains("[AVG: 2.00]"));
+
+		elt.values = [f64::NAN, f64::NEG_INFINITY].into_iter().collect();
+		assert!(finite_series(&plot_series(&elt, &settings)).is_none());
+		assert!(!render_counter(&elt, &settings).contains('┤'));
+	}
+}
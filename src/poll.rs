@@ -0,0 +1,441 @@
+use chrono::{DateTime, Local, NaiveTime};
+use color_eyre::eyre::eyre;
+use std::{
+	fmt,
+	future::Future,
+	str::FromStr,
+	time::{Duration, Instant},
+};
+
+/// Measured interval between two consecutive polls compared to the configured one
+#[derive(Clone, Copy, Debug)]
+pub struct IntervalDrift {
+	/// Actual time elapsed between ticks
+	pub measured: Duration,
+	/// Configured poll interval
+	pub target: Duration,
+}
+
+impl IntervalDrift {
+	/// Computes drift from timestamps of two consecutive ticks
+	pub fn new(prev: Instant, now: Instant, target: Duration) -> Self {
+		Self { measured: now.saturating_duration_since(prev), target }
+	}
+
+	/// Difference between measured and target interval in milliseconds
+	pub fn drift_ms(&self) -> i64 {
+		self.measured.as_millis() as i64 - self.target.as_millis() as i64
+	}
+}
+
+impl fmt::Display for IntervalDrift {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"interval: {:.2}s (target {:.2}s, drift {:+}ms)",
+			self.measured.as_secs_f64(),
+			self.target.as_secs_f64(),
+			self.drift_ms()
+		)
+	}
+}
+
+/// Source of sample times: the monotonic clock drives rates, the wall clock only stamps the output
+pub trait Clock {
+	/// Monotonic time, which never jumps
+	fn now(&self) -> Instant;
+	/// Wall clock time, which may be stepped, e.g. by NTP
+	fn wall(&self) -> DateTime<Local>;
+}
+
+/// Clock of the system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+
+	fn wall(&self) -> DateTime<Local> {
+		Local::now()
+	}
+}
+
+/// Measures time between successful samples using a monotonic clock, so wall clock
+/// adjustments (e.g. NTP steps) cannot affect computed rates
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SampleClock {
+	last: Option<Instant>,
+}
+
+impl SampleClock {
+	/// Returns time elapsed since the previous sample (or `first` if there were none yet);
+	/// returns None if no measurable time has passed and the sample should be skipped
+	pub fn elapsed(&self, now: Instant, first: Duration) -> Option<Duration> {
+		match self.last {
+			// Rates are computed with millisecond precision
+			Some(last) => now.checked_duration_since(last).filter(|d| d.as_millis() > 0),
+			None => Some(first),
+		}
+	}
+
+	/// Records a successful sample taken at `now`
+	pub fn record(&mut self, now: Instant) {
+		self.last = Some(now);
+	}
+}
+
+/// Longest pause between polls while the endpoint keeps failing
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Slows polling down after consecutive errors, doubling the interval each time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Backoff {
+	failures: u32,
+}
+
+impl Backoff {
+	/// Returns the pause before the next poll, it never exceeds `MAX_BACKOFF` unless `interval` itself does
+	pub fn interval(&self, interval: Duration) -> Duration {
+		let backed_off = interval.saturating_mul(1_u32.checked_shl(self.failures).unwrap_or(u32::MAX));
+		backed_off.min(MAX_BACKOFF.max(interval))
+	}
+
+	pub fn failure(&mut self) {
+		self.failures = self.failures.saturating_add(1);
+	}
+
+	/// Restores the configured cadence
+	pub fn success(&mut self) {
+		self.failures = 0;
+	}
+}
+
+/// Right after startup polls are this many times less frequent than configured
+pub const SLOW_START_FACTOR: f64 = 4.0;
+
+/// Returns the interval during the warmup after startup: it starts `SLOW_START_FACTOR` times longer and shrinks
+/// linearly to `interval` by the end of the warmup, so a freshly started Rspamd is not hammered
+pub fn slow_start_interval(interval: Duration, warmup: Duration, since_start: Duration) -> Duration {
+	if since_start >= warmup {
+		return interval;
+	}
+
+	let remaining = 1.0 - since_start.as_secs_f64() / warmup.as_secs_f64();
+	// Unlike `mul_f64`, saturates on huge intervals instead of panicking
+	Duration::try_from_secs_f64(interval.as_secs_f64() * (1.0 + (SLOW_START_FACTOR - 1.0) * remaining))
+		.unwrap_or(Duration::MAX)
+}
+
+/// Runs a single poll, turning it into a transient error if it does not finish before the deadline
+///
+/// The outer result of the poll carries fatal errors, whilst the inner one carries transient errors.
+pub async fn run_tick<T, F>(deadline: Option<Duration>, tick: F) -> color_eyre::Result<color_eyre::Result<T>>
+where
+	F: Future<Output = color_eyre::Result<color_eyre::Result<T>>>,
+{
+	match deadline {
+		Some(deadline) => tokio::time::timeout(deadline, tick)
+			.await
+			.unwrap_or_else(|_| Ok(Err(eyre!("poll has not finished in {:.2}s", deadline.as_secs_f64())))),
+		None => tick.await,
+	}
+}
+
+/// Whether the display should be refreshed on a specific tick (counting from zero) when
+/// rendering only every `every` ticks
+pub fn should_render(tick: u64, every: u32) -> bool {
+	tick.is_multiple_of(u64::from(every.max(1)))
+}
+
+/// Poll interval used during a specific time range of the day
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduleEntry {
+	/// Start of the range (inclusive)
+	pub start: NaiveTime,
+	/// End of the range (exclusive), may be less than start for ranges spanning midnight
+	pub end: NaiveTime,
+	/// Poll interval within the range
+	pub interval: Duration,
+}
+
+impl ScheduleEntry {
+	/// Whether the entry is active at a specific time of the day
+	pub fn contains(&self, time: NaiveTime) -> bool {
+		if self.start <= self.end {
+			time >= self.start && time < self.end
+		} else {
+			time >= self.start || time < self.end
+		}
+	}
+}
+
+/// Parses a duration like `500ms`, `2s`, `1m` or `1h`, a bare number is taken as seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+	let s = s.trim();
+	let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+	let (number, unit) = s.split_at(split);
+	let number = number
+		.trim()
+		.parse::<f64>()
+		.ok()
+		.filter(|n| n.is_finite() && *n >= 0.0)
+		.ok_or_else(|| format!("invalid duration: {}", s))?;
+	let scale = match unit {
+		"ms" => 0.001,
+		"" | "s" => 1.0,
+		"m" => 60.0,
+		"h" => 3600.0,
+		_ => return Err(format!("unknown duration unit {} in {}, expected ms, s, m or h", unit, s)),
+	};
+
+	Duration::try_from_secs_f64(number * scale).map_err(|_| format!("duration is too long: {}", s))
+}
+
+/// Parses a duration like `parse_duration`, rejecting zero, which makes no sense for intervals and timeouts
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+	let interval = parse_duration(s)?;
+	if interval.is_zero() {
+		return Err(format!("interval must be positive: {}", s.trim()));
+	}
+
+	Ok(interval)
+}
+
+/// Poll intervals depending on the local time of the day
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Schedule {
+	pub entries: Vec<ScheduleEntry>,
+}
+
+impl Schedule {
+	/// Returns the interval active at a specific time, the first matching entry wins
+	pub fn interval_at(&self, time: NaiveTime, default: Duration) -> Duration {
+		self.entries
+			.iter()
+			.find(|entry| entry.contains(time))
+			.map_or(default, |entry| entry.interval)
+	}
+}
+
+impl FromStr for Schedule {
+	type Err = String;
+
+	/// Parses a schedule like `22:00-06:00=10,12:00-13:00=500ms`, intervals are durations like `--interval`
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let parse_time =
+			|t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|e| format!("invalid time {}: {}", t, e));
+		let entries = s
+			.split(',')
+			.map(|entry| {
+				let (range, interval) = entry
+					.split_once('=')
+					.ok_or_else(|| format!("expected start-end=interval: {}", entry))?;
+				let (start, end) = range.split_once('-').ok_or_else(|| format!("expected start-end: {}", range))?;
+				let interval = parse_interval(interval)?;
+
+				Ok::<_, String>(ScheduleEntry { start: parse_time(start)?, end: parse_time(end)?, interval })
+			})
+			.collect::<Result<Vec<_>, String>>()?;
+
+		Ok(Self { entries })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		counters::RspamdStat,
+		poll::{
+			parse_duration, parse_interval, run_tick, should_render, slow_start_interval, Backoff, Clock,
+			IntervalDrift, SampleClock, Schedule, MAX_BACKOFF, SLOW_START_FACTOR,
+		},
+	};
+	use chrono::{DateTime, Local, NaiveTime};
+	use std::{
+		cell::Cell,
+		time::{Duration, Instant},
+	};
+
+	/// Clock advanced by hand, its wall clock could be stepped independently of the monotonic one
+	struct FakeClock {
+		now: Cell<Instant>,
+		wall: Cell<DateTime<Local>>,
+	}
+
+	impl FakeClock {
+		fn advance(&self, monotonic: Duration, wall: chrono::Duration) {
+			self.now.set(self.now.get() + monotonic);
+			self.wall.set(self.wall.get() + wall);
+		}
+	}
+
+	impl Clock for FakeClock {
+		fn now(&self) -> Instant {
+			self.now.get()
+		}
+
+		fn wall(&self) -> DateTime<Local> {
+			self.wall.get()
+		}
+	}
+
+	#[test]
+	fn parse_duration_test() {
+		assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+		assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+		assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+		assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+		assert!(parse_duration("2x").is_err());
+		assert!(parse_duration("-1s").is_err());
+		// Parses fine, but does not fit into a Duration
+		assert!(parse_duration("18446744073709551615h").unwrap_err().contains("too long"));
+		assert!(parse_duration("ms").is_err());
+		assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+		assert!(parse_interval("0").is_err());
+		assert!(parse_interval("0ms").unwrap_err().contains("must be positive"));
+		assert_eq!(parse_interval("500ms"), Ok(Duration::from_millis(500)));
+	}
+
+	#[test]
+	fn interval_drift_test() {
+		let start = Instant::now();
+		let target = Duration::from_secs(1);
+		let late = IntervalDrift::new(start, start + Duration::from_millis(1012), target);
+		assert_eq!(late.drift_ms(), 12);
+		assert_eq!(late.to_string(), "interval: 1.01s (target 1.00s, drift +12ms)");
+		let early = IntervalDrift::new(start, start + Duration::from_millis(990), target);
+		assert_eq!(early.drift_ms(), -10);
+		assert_eq!(early.to_string(), "interval: 0.99s (target 1.00s, drift -10ms)");
+	}
+
+	#[test]
+	fn sample_clock_test() {
+		let start = Instant::now();
+		let interval = Duration::from_secs(1);
+		let clock = FakeClock { now: Cell::new(start), wall: Cell::new(Local::now()) };
+		let mut sample_clock = SampleClock::default();
+		let mut stats = RspamdStat::new(4, false);
+		let mut stamps = Vec::new();
+
+		// A second passes between the samples, whilst the wall clock is stepped back by an hour and then
+		// forward by two, e.g. by NTP
+		for (tick, wall_step, reject) in [(0, 0, 0), (1, -3600, 10), (1, 7200, 30)] {
+			clock.advance(interval * tick, chrono::Duration::seconds(wall_step));
+			let elapsed = sample_clock.elapsed(clock.now(), interval).unwrap();
+			assert_eq!(elapsed, interval);
+			let json = serde_json::json!({ "actions": { "reject": reject } });
+			stats.update_from_json(json, elapsed).unwrap();
+			sample_clock.record(clock.now());
+			stamps.push(clock.wall());
+		}
+		assert_eq!(stats.spam_stats.values, [10_f64, 20_f64]);
+		// Only the output timestamps follow the steps
+		assert!(stamps[1] < stamps[0] && stamps[2] > stamps[1]);
+
+		let last = start + Duration::from_secs(2);
+		assert!(sample_clock.elapsed(last, interval).is_none());
+		assert!(sample_clock.elapsed(last - Duration::from_millis(500), interval).is_none());
+		assert!(sample_clock.elapsed(last + Duration::from_micros(10), interval).is_none());
+	}
+
+	#[test]
+	fn slow_start_test() {
+		let interval = Duration::from_secs(1);
+		let warmup = Duration::from_secs(60);
+		let ramp = [0, 15, 30, 45, 59, 60, 120]
+			.into_iter()
+			.map(|secs| slow_start_interval(interval, warmup, Duration::from_secs(secs)))
+			.collect::<Vec<_>>();
+
+		assert_eq!(ramp[0], interval.mul_f64(SLOW_START_FACTOR));
+		assert_eq!(ramp[2], Duration::from_millis(2500));
+		assert!(ramp.windows(2).all(|pair| pair[0] >= pair[1]));
+		assert!(ramp[4] > interval && ramp[4] < Duration::from_millis(1100));
+		assert_eq!(ramp[5], interval);
+		assert_eq!(ramp[6], interval);
+		assert_eq!(slow_start_interval(interval, Duration::ZERO, Duration::ZERO), interval);
+		assert_eq!(slow_start_interval(Duration::MAX, warmup, Duration::ZERO), Duration::MAX);
+	}
+
+	#[test]
+	fn backoff_test() {
+		let interval = Duration::from_secs(1);
+		let mut backoff = Backoff::default();
+		assert_eq!(backoff.interval(interval), interval);
+
+		for expected in [2, 4, 8, 16, 32, 60, 60] {
+			backoff.failure();
+			assert_eq!(backoff.interval(interval), Duration::from_secs(expected));
+		}
+		for _ in 0..100 {
+			backoff.failure();
+		}
+		assert_eq!(backoff.interval(interval), MAX_BACKOFF);
+		// Intervals longer than the cap are kept as is
+		assert_eq!(backoff.interval(Duration::from_secs(120)), Duration::from_secs(120));
+
+		backoff.success();
+		assert_eq!(backoff.interval(interval), interval);
+	}
+
+	#[tokio::test]
+	async fn tick_deadline_test() {
+		let deadline = Some(Duration::from_millis(20));
+		let slow_parse = async {
+			tokio::time::sleep(Duration::from_secs(1)).await;
+			Ok::<color_eyre::Result<()>, color_eyre::Report>(Ok(()))
+		};
+		let res = run_tick(deadline, slow_parse).await.unwrap();
+		assert!(res.unwrap_err().to_string().contains("has not finished"));
+
+		let fast_parse = async { Ok::<color_eyre::Result<i32>, color_eyre::Report>(Ok(42)) };
+		assert_eq!(run_tick(deadline, fast_parse).await.unwrap().unwrap(), 42);
+	}
+
+	#[test]
+	fn render_every_test() {
+		let mut collected = 0;
+		let mut rendered = Vec::new();
+		for tick in 0..10_u64 {
+			collected += 1;
+			if should_render(tick, 3) {
+				rendered.push(tick);
+			}
+		}
+		assert_eq!(collected, 10);
+		assert_eq!(rendered, vec![0, 3, 6, 9]);
+		assert!((0..10).all(|tick| should_render(tick, 1)));
+		assert!((0..10).all(|tick| should_render(tick, 0)));
+	}
+
+	#[test]
+	fn schedule_test() {
+		let schedule = "22:00-06:00=10,12:00-13:00=5".parse::<Schedule>().unwrap();
+		let default = Duration::from_secs(1);
+		let at = |h, m| schedule.interval_at(NaiveTime::from_hms_opt(h, m, 0).unwrap(), default);
+
+		assert_eq!(at(23, 30), Duration::from_secs(10));
+		assert_eq!(at(3, 0), Duration::from_secs(10));
+		assert_eq!(at(6, 0), default);
+		assert_eq!(at(12, 30), Duration::from_secs(5));
+		assert_eq!(at(13, 0), default);
+		assert_eq!(at(18, 0), default);
+
+		// Units are the same as of --interval
+		let schedule = "09:00-17:00=500ms,17:00-18:00=2m".parse::<Schedule>().unwrap();
+		let at = |h, m| schedule.interval_at(NaiveTime::from_hms_opt(h, m, 0).unwrap(), default);
+		assert_eq!(at(12, 0), Duration::from_millis(500));
+		assert_eq!(at(17, 30), Duration::from_secs(120));
+
+		assert!("22:00=10".parse::<Schedule>().is_err());
+		assert!("22:00-06:00=0".parse::<Schedule>().is_err());
+		assert!("22:00-06:00=inf".parse::<Schedule>().is_err());
+		assert!("22:00-06:00=-5".parse::<Schedule>().is_err());
+		assert!("22:00-06:00=1e30".parse::<Schedule>().is_err());
+		assert!("22:00-06:00=18446744073709551615h".parse::<Schedule>().is_err());
+		assert!("22:00-06:00=0ms".parse::<Schedule>().is_err());
+		assert!("25:00-06:00=10".parse::<Schedule>().is_err());
+	}
+}
@@ -0,0 +1,634 @@
+use crate::{
+	counters::{Counter, CounterMeta, KnownCounter, RspamdStat},
+	poll::parse_interval,
+	shared::SharedStat,
+};
+use color_eyre::eyre::eyre;
+use flate2::{write::GzEncoder, Compression};
+use hyper::{
+	header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE},
+	service::{make_service_fn, service_fn},
+	Body, Method, Request, Response, Server, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use std::{
+	convert::Infallible,
+	fmt::Write,
+	future::Future,
+	io,
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+};
+
+/// Content type of the Prometheus text exposition format
+pub const EXPOSITION_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Page with the current values and small charts of the exported counters, refreshed from `/counters`
+const DASHBOARD_TEMPLATE: &str = include_str!("dashboard.html");
+
+/// Returns the name of a gauge exported for a counter
+pub fn metric_name(counter: &KnownCounter) -> String {
+	let suffix = match counter {
+		KnownCounter::AvgTime | KnownCounter::ScanP50 | KnownCounter::ScanP95 | KnownCounter::ScanP99 => "_seconds",
+		KnownCounter::Connections | KnownCounter::ChunksOversized => "_per_sec",
+		KnownCounter::HamPercentage | KnownCounter::BytesAllocated | KnownCounter::Derived(_) => "",
+		_ => "_msgs_per_sec",
+	};
+
+	format!("rspamd_mon_{}{}", sanitize_name(counter.name()), suffix)
+}
+
+/// Replaces characters not allowed in Prometheus and StatsD metric names with underscores
+///
+/// Derived metric names come from the embedder and may contain anything. Colons are replaced too, as Prometheus
+/// reserves them for recording rules and StatsD separates the value with them.
+pub fn sanitize_name(name: &str) -> String {
+	name.chars()
+		.map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+		.collect()
+}
+
+/// Escapes a label value, action names come from Rspamd and may contain quotes
+fn escape_label_value(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serializes stats in Prometheus text exposition format, counters without values are omitted
+pub fn to_prometheus(stat: &RspamdStat) -> String {
+	let mut out = String::new();
+
+	// Scraping more often than Rspamd is polled only returns the same samples again
+	if let Some(interval) = stat.poll_interval {
+		let _ = writeln!(out, "# poll_interval_seconds {}", interval.as_secs_f64());
+	}
+
+	// Reported even when no counter has fresh values, so alerts can tell an unreachable Rspamd from a quiet one
+	if let Some(up) = stat.scrapes.back() {
+		let name = "rspamd_up";
+		let _ = writeln!(out, "# HELP {} Whether the last scrape of Rspamd succeeded", name);
+		let _ = writeln!(out, "# TYPE {} gauge", name);
+		let _ = writeln!(out, "{} {}", name, u8::from(*up));
+	}
+
+	for elt in stat.selected_elements() {
+		if let Some(value) = elt.values.back() {
+			let name = metric_name(&elt.kind);
+			let _ = writeln!(out, "# HELP {} {}", name, elt.label());
+			let _ = writeln!(out, "# TYPE {} gauge", name);
+			let _ = writeln!(out, "{} {}", name, value);
+		}
+	}
+
+	if stat.export_action_rates && !stat.action_rates.is_empty() {
+		let name = "rspamd_mon_action_rate";
+		let _ = writeln!(out, "# HELP {} Rate of each Rspamd action in messages per second", name);
+		let _ = writeln!(out, "# TYPE {} gauge", name);
+		for (action, rate) in &stat.action_rates {
+			let _ = writeln!(out, "{}{{action=\"{}\"}} {}", name, escape_label_value(action), rate);
+		}
+	}
+
+	if let Some(score) = stat.health() {
+		let name = "rspamd_mon_health_score";
+		let _ = writeln!(out, "# HELP {} Composite health score from 0 (worst) to 100 (best)", name);
+		let _ = writeln!(out, "# TYPE {} gauge", name);
+		let _ = writeln!(out, "{} {}", name, score);
+	}
+
+	// Counters read from `/statreset` are not cumulative
+	let raw = if stat.reset {
+		Vec::new()
+	} else {
+		vec![
+			(KnownCounter::Scanned, stat.scanned, "messages scanned"),
+			(KnownCounter::Learned, stat.learned, "messages learned"),
+		]
+	};
+	for (counter, value, help) in raw {
+		if let Some(value) = value.filter(|_| stat.is_selected(&counter)) {
+			let name = counter.name();
+			let _ = writeln!(out, "# HELP rspamd_mon_{}_total Rspamd {}", name, help);
+			let _ = writeln!(out, "# TYPE rspamd_mon_{}_total counter", name);
+			let _ = writeln!(out, "rspamd_mon_{}_total {}", name, value);
+		}
+	}
+
+	let histogram = &stat.scan_histogram;
+	if histogram.count > 0 {
+		let name = "rspamd_mon_scan_time_seconds";
+		let _ = writeln!(out, "# HELP {} Rspamd scan times reported on each poll", name);
+		let _ = writeln!(out, "# TYPE {} histogram", name);
+		let bounds = histogram
+			.bounds()
+			.iter()
+			.map(|bound| bound.to_string())
+			.chain(["+Inf".to_owned()]);
+		for (le, count) in bounds.zip(histogram.cur_value()) {
+			let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+		}
+		let _ = writeln!(out, "{}_sum {}", name, histogram.sum);
+		let _ = writeln!(out, "{}_count {}", name, histogram.count);
+	}
+
+	out
+}
+
+/// Serializes the whole stored history of a counter requested as `counter=<name>&format=json|csv`
+fn history(stat: &RspamdStat, query: &str) -> Result<(&'static str, String), (StatusCode, String)> {
+	let mut counter = None;
+	let mut format = "json".to_owned();
+	for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+		let value = percent_decode_str(value).decode_utf8_lossy().into_owned();
+		match key {
+			"counter" => counter = Some(value),
+			"format" => format = value,
+			_ => {},
+		}
+	}
+
+	let name = counter.ok_or_else(|| (StatusCode::BAD_REQUEST, "missing counter parameter\n".to_owned()))?;
+	let elt = stat
+		.selected_elements()
+		.into_iter()
+		.find(|elt| elt.kind.name() == name)
+		.ok_or_else(|| (StatusCode::NOT_FOUND, format!("unknown counter: {}\n", name)))?;
+
+	match format.as_str() {
+		"json" => {
+			let values = elt.values.iter().cloned().collect::<Vec<_>>();
+			Ok(("application/json", serde_json::json!({ "counter": name, "values": values }).to_string()))
+		},
+		"csv" => {
+			let mut out = format!("{}\n", name);
+			for value in &elt.values {
+				let _ = writeln!(out, "{}", value);
+			}
+			Ok(("text/csv", out))
+		},
+		_ => Err((StatusCode::BAD_REQUEST, format!("unknown format: {}\n", format))),
+	}
+}
+
+/// Averages a counter over the latest `window` requested as `counter=<name>&window=<seconds>`
+///
+/// The window is mapped to the number of stored values using the poll interval, it is capped by the stored history.
+fn window_rate(stat: &RspamdStat, query: &str) -> Result<String, (StatusCode, String)> {
+	let mut counter = None;
+	let mut window = None;
+	for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+		let value = percent_decode_str(value).decode_utf8_lossy().into_owned();
+		match key {
+			"counter" => counter = Some(value),
+			"window" => window = Some(value),
+			_ => {},
+		}
+	}
+
+	let name = counter.ok_or_else(|| (StatusCode::BAD_REQUEST, "missing counter parameter\n".to_owned()))?;
+	let window = window.ok_or_else(|| (StatusCode::BAD_REQUEST, "missing window parameter\n".to_owned()))?;
+	let window = parse_interval(&window).map_err(|e| (StatusCode::BAD_REQUEST, format!("{}\n", e)))?;
+	let elt = stat
+		.selected_elements()
+		.into_iter()
+		.find(|elt| elt.kind.name() == name)
+		.ok_or_else(|| (StatusCode::NOT_FOUND, format!("unknown counter: {}\n", name)))?;
+	let interval = stat
+		.poll_interval
+		.ok_or_else(|| (StatusCode::SERVICE_UNAVAILABLE, "poll interval is not known yet\n".to_owned()))?;
+
+	let wanted = (window.as_secs_f64() / interval.as_secs_f64()).ceil().max(1.0) as usize;
+	let samples = elt
+		.values
+		.iter()
+		.rev()
+		.take(wanted)
+		.filter(|value| value.is_finite())
+		.collect::<Vec<_>>();
+	let rate = (!samples.is_empty()).then(|| samples.iter().copied().sum::<f64>() / samples.len() as f64);
+
+	Ok(serde_json::json!({
+		"counter": name,
+		"window": window.as_secs_f64(),
+		"samples": samples.len(),
+		"rate": rate,
+	})
+	.to_string())
+}
+
+/// Returns counters exported at `/metrics` with their windows
+fn exported_counters(stat: &RspamdStat) -> Vec<CounterMeta> {
+	let selected = stat
+		.selected_elements()
+		.into_iter()
+		.map(|elt| elt.kind.name())
+		.collect::<Vec<_>>();
+	stat.counters_meta()
+		.into_iter()
+		.filter(|meta| selected.contains(&meta.name.as_str()))
+		.collect()
+}
+
+fn escape_html(s: &str) -> String {
+	s.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
+/// Renders the dashboard page, the current values are filled in, so the page is useful without scripts too
+fn dashboard(stat: &RspamdStat) -> String {
+	let mut rows = String::new();
+	for meta in exported_counters(stat) {
+		let current = meta.current.map(|value| format!("{:.2}", value)).unwrap_or_default();
+		let _ = writeln!(
+			rows,
+			"<tr><td>{}</td><td>{}</td><td class=\"value\">{}</td><td></td></tr>",
+			escape_html(&meta.name),
+			escape_html(&meta.label),
+			current
+		);
+	}
+
+	DASHBOARD_TEMPLATE.replace("{{rows}}", &rows)
+}
+
+/// Whether the `Accept-Encoding` header allows gzip, `gzip;q=0` refuses it explicitly
+fn accepts_gzip(accept_encoding: &str) -> bool {
+	accept_encoding.split(',').any(|coding| {
+		let mut params = coding.split(';').map(str::trim);
+		let name = params.next().unwrap_or_default();
+		let refused = params.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+
+		(name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+	})
+}
+
+fn gzip(body: &[u8]) -> io::Result<Vec<u8>> {
+	let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+	io::Write::write_all(&mut encoder, body)?;
+	encoder.finish()
+}
+
+async fn handle(req: Request<Body>, stats: SharedStat, paused: Arc<AtomicBool>) -> Result<Response<Body>, Infallible> {
+	let resp = match (req.method(), req.uri().path()) {
+		// Alerts do not fire on a target which is down for maintenance, polling goes on meanwhile
+		(&Method::GET, "/metrics") if paused.load(Ordering::Relaxed) => Response::builder()
+			.status(StatusCode::SERVICE_UNAVAILABLE)
+			.body(Body::from("exporting is paused\n")),
+		(&Method::GET, "/metrics") => {
+			let body = to_prometheus(&stats.load());
+			let resp = Response::builder().header(CONTENT_TYPE, EXPOSITION_CONTENT_TYPE);
+			let compress = req
+				.headers()
+				.get(ACCEPT_ENCODING)
+				.and_then(|v| v.to_str().ok())
+				.is_some_and(accepts_gzip);
+
+			match compress.then(|| gzip(body.as_bytes())) {
+				Some(Ok(compressed)) => resp.header(CONTENT_ENCODING, "gzip").body(Body::from(compressed)),
+				_ => resp.body(Body::from(body)),
+			}
+		},
+		(&Method::GET, "/") => Response::builder()
+			.header(CONTENT_TYPE, "text/html; charset=utf-8")
+			.body(Body::from(dashboard(&stats.load()))),
+		(&Method::GET, "/counters") => Response::builder()
+			.header(CONTENT_TYPE, "application/json")
+			.body(Body::from(serde_json::to_string(&exported_counters(&stats.load())).unwrap_or_default())),
+		(&Method::GET, "/rate") => match window_rate(&stats.load(), req.uri().query().unwrap_or_default()) {
+			Ok(body) => Response::builder()
+				.header(CONTENT_TYPE, "application/json")
+				.body(Body::from(body)),
+			Err((status, body)) => Response::builder().status(status).body(Body::from(body)),
+		},
+		(&Method::POST, "/admin/pause") => {
+			let was_paused = paused.fetch_xor(true, Ordering::Relaxed);
+			Response::new(Body::from(if was_paused { "resumed\n" } else { "paused\n" }))
+		},
+		(&Method::GET, "/history") => match history(&stats.load(), req.uri().query().unwrap_or_default()) {
+			Ok((content_type, body)) => Response::builder().header(CONTENT_TYPE, content_type).body(Body::from(body)),
+			Err((status, body)) => Response::builder().status(status).body(Body::from(body)),
+		},
+		_ => Response::builder()
+			.status(StatusCode::NOT_FOUND)
+			.body(Body::from("not found\n")),
+	};
+
+	Ok(resp.unwrap_or_else(|_| Response::new(Body::empty())))
+}
+
+/// Binds the metrics endpoint, returns the bound address and the future serving requests
+///
+/// `POST /admin/pause` toggles exporting: while paused `/metrics` replies with 503, whilst polling goes on.
+pub fn serve(
+	addr: SocketAddr,
+	stats: SharedStat,
+) -> color_eyre::Result<(SocketAddr, impl Future<Output = hyper::Result<()>>)> {
+	let paused = Arc::new(AtomicBool::new(false));
+	let make_svc = make_service_fn(move |_conn| {
+		let stats = stats.clone();
+		let paused = paused.clone();
+		async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, stats.clone(), paused.clone()))) }
+	});
+	let server = Server::try_bind(&addr)
+		.map_err(|e| eyre!("cannot bind metrics endpoint to {}: {}", addr, e))?
+		.serve(make_svc);
+
+	Ok((server.local_addr(), server))
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		counters::{KnownCounter, RspamdStat},
+		prometheus::{accepts_gzip, escape_html, escape_label_value, metric_name, serve, to_prometheus},
+		shared::SharedStat,
+	};
+	use flate2::read::GzDecoder;
+	use std::{io::Read, time::Duration};
+
+	#[test]
+	fn to_prometheus_test() {
+		let mut stats = RspamdStat::new(4, false);
+		assert_eq!(to_prometheus(&stats), "");
+		assert_eq!(stats.health(), None);
+
+		let elapsed = Duration::from_secs(1);
+		let first = serde_json::json!({"scanned": 1100, "learned": 5, "actions": {"reject": 100, "no action": 1000}});
+		let second = serde_json::json!({"scanned": 1220, "learned": 6, "actions": {"reject": 120, "no action": 1100}});
+		stats.ingest(&first, elapsed).unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+
+		let text = to_prometheus(&stats);
+		assert!(text.contains("# TYPE rspamd_mon_spam_msgs_per_sec gauge\nrspamd_mon_spam_msgs_per_sec 20\n"));
+		assert!(text.contains("rspamd_mon_total_msgs_per_sec 120\n"));
+		assert!(text.contains("# TYPE rspamd_mon_scanned_total counter\nrspamd_mon_scanned_total 1220\n"));
+		assert!(text.contains("rspamd_mon_learned_total 6\n"));
+		assert!(text.contains("rspamd_mon_ham_percentage 83.33"));
+		assert!(text.contains("# TYPE rspamd_mon_health_score gauge\n"));
+		assert_eq!(metric_name(&KnownCounter::BytesAllocated), "rspamd_mon_bytes_allocated");
+		assert_eq!(metric_name(&KnownCounter::ChunksOversized), "rspamd_mon_chunks_oversized_per_sec");
+		assert_eq!(metric_name(&KnownCounter::Derived("spam/min ratio".into())), "rspamd_mon_spam_min_ratio");
+		// No scan times were reported
+		assert!(!text.contains(&metric_name(&KnownCounter::AvgTime)));
+		assert!(!text.contains("rspamd_mon_scan_time_seconds"));
+
+		stats
+			.ingest(
+				&serde_json::json!({"scanned": 1220, "learned": 6, "actions": {}, "scan_times": [0.25, 0.5, 0.5, 3.0]}),
+				elapsed,
+			)
+			.unwrap();
+		let text = to_prometheus(&stats);
+		assert!(text.contains("# TYPE rspamd_mon_scan_time_seconds histogram\n"));
+		assert!(text.contains("rspamd_mon_scan_time_seconds_bucket{le=\"0.1\"} 0\n"));
+		assert!(text.contains("rspamd_mon_scan_time_seconds_bucket{le=\"0.25\"} 1\n"));
+		assert!(text.contains("rspamd_mon_scan_time_seconds_bucket{le=\"2\"} 3\n"));
+		assert!(text.contains("rspamd_mon_scan_time_seconds_bucket{le=\"+Inf\"} 4\n"));
+		assert!(text.contains("rspamd_mon_scan_time_seconds_sum 4.25\n"));
+		assert!(text.contains("rspamd_mon_scan_time_seconds_count 4\n"));
+
+		stats.selection = Some("spam,learned".parse().unwrap());
+		let text = to_prometheus(&stats);
+		assert!(text.contains("rspamd_mon_spam_msgs_per_sec "));
+		assert!(text.contains("rspamd_mon_learned_total 6\n"));
+		assert!(!text.contains("rspamd_mon_total_msgs_per_sec"));
+		assert!(!text.contains("rspamd_mon_scanned_total"));
+		assert!(!text.contains("# poll_interval_seconds"));
+
+		stats.poll_interval = Some(Duration::from_millis(2500));
+		assert!(to_prometheus(&stats).starts_with("# poll_interval_seconds 2.5\n"));
+	}
+
+	#[test]
+	fn action_rates_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		let first =
+			serde_json::json!({"actions": {"reject": 100, "greylist": 50, "no_action": 1000, "add header": 10}});
+		let second =
+			serde_json::json!({"actions": {"reject": 120, "greylist": 80, "no_action": 1100, "add header": 10}});
+		stats.ingest(&first, elapsed).unwrap();
+		stats.ingest(&second, elapsed).unwrap();
+		assert!(!to_prometheus(&stats).contains("rspamd_mon_action_rate"));
+
+		stats.export_action_rates = true;
+		let text = to_prometheus(&stats);
+		assert!(text.contains("# TYPE rspamd_mon_action_rate gauge\n"));
+		assert!(text.contains("rspamd_mon_action_rate{action=\"reject\"} 20\n"));
+		assert!(text.contains("rspamd_mon_action_rate{action=\"greylist\"} 30\n"));
+		// Keys are normalized, like for the spam, ham and junk buckets
+		assert!(text.contains("rspamd_mon_action_rate{action=\"no action\"} 100\n"));
+		assert!(text.contains("rspamd_mon_action_rate{action=\"add header\"} 0\n"));
+		assert_eq!(escape_label_value("a\"b\\c"), "a\\\"b\\\\c");
+	}
+
+	#[test]
+	fn rspamd_up_test() {
+		let mut stats = RspamdStat::new(4, false);
+		assert!(!to_prometheus(&stats).contains("rspamd_up"));
+
+		stats.record_scrape(true);
+		assert!(to_prometheus(&stats).contains("# TYPE rspamd_up gauge\nrspamd_up 1\n"));
+		// No counter has values, the failure is reported anyway
+		stats.record_scrape(false);
+		assert!(to_prometheus(&stats).contains("rspamd_up 0\n"));
+		stats.record_scrape(true);
+		assert!(to_prometheus(&stats).contains("rspamd_up 1\n"));
+	}
+
+	#[tokio::test]
+	async fn serve_test() {
+		let stats = SharedStat::new(RspamdStat::new(4, false));
+		let (addr, server) = serve("127.0.0.1:0".parse().unwrap(), stats.clone()).unwrap();
+		tokio::spawn(server);
+
+		// Scrapes before the first poll get an empty body
+		let resp = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap();
+		assert_eq!(resp.status(), reqwest::StatusCode::OK);
+		assert_eq!(resp.text().await.unwrap(), "");
+
+		let mut stat = RspamdStat::new(4, false);
+		stat.ingest(&serde_json::json!({"scanned": 42, "actions": {}}), Duration::from_secs(1))
+			.unwrap();
+		stats.publish(stat);
+		let text = reqwest::get(format!("http://{}/metrics", addr))
+			.await
+			.unwrap()
+			.text()
+			.await
+			.unwrap();
+		assert!(text.contains("rspamd_mon_scanned_total 42\n"));
+
+		let resp = reqwest::get(format!("http://{}/other", addr)).await.unwrap();
+		assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+	}
+
+	#[tokio::test]
+	async fn rate_test() {
+		let mut stat = RspamdStat::new(4, false);
+		for reject in [0, 10, 30, 60, 100, 150] {
+			stat.ingest(&serde_json::json!({"actions": {"reject": reject}}), Duration::from_secs(2))
+				.unwrap();
+		}
+		// The oldest rate went out of the window
+		assert_eq!(stat.spam_stats.values, [10.0, 15.0, 20.0, 25.0]);
+		let stats = SharedStat::new(stat.clone());
+		let (addr, server) = serve("127.0.0.1:0".parse().unwrap(), stats.clone()).unwrap();
+		tokio::spawn(server);
+		let get = |query: &str| reqwest::get(format!("http://{}/rate?{}", addr, query));
+
+		// Rates are only averaged once the poll interval is known
+		assert_eq!(get("counter=spam&window=4").await.unwrap().status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+		stat.poll_interval = Some(Duration::from_secs(2));
+		stats.publish(stat);
+
+		let rate = |body: String| serde_json::from_str::<serde_json::Value>(&body).unwrap();
+		let resp = get("counter=spam&window=4").await.unwrap();
+		assert_eq!(resp.headers()["content-type"], "application/json");
+		let json = rate(resp.text().await.unwrap());
+		assert_eq!(json, serde_json::json!({"counter": "spam", "window": 4.0, "samples": 2, "rate": 22.5}));
+		// Partial intervals take one more value, windows longer than the history take all of it
+		let json = rate(get("counter=spam&window=5s").await.unwrap().text().await.unwrap());
+		assert_eq!((json["samples"].as_u64(), json["rate"].as_f64()), (Some(3), Some(20.0)));
+		let json = rate(get("counter=spam&window=300").await.unwrap().text().await.unwrap());
+		assert_eq!((json["samples"].as_u64(), json["rate"].as_f64()), (Some(4), Some(17.5)));
+
+		assert_eq!(get("counter=nope&window=4").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+		assert_eq!(get("counter=spam").await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+		assert_eq!(get("counter=spam&window=0").await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+		assert_eq!(get("window=4").await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+	}
+
+	#[tokio::test]
+	async fn dashboard_test() {
+		let mut stat = RspamdStat::new(4, false);
+		for reject in [100, 120] {
+			stat.ingest(&serde_json::json!({"scanned": 1000, "actions": {"reject": reject}}), Duration::from_secs(1))
+				.unwrap();
+		}
+		stat.selection = Some("spam,total".parse().unwrap());
+		let stats = SharedStat::new(stat);
+		let (addr, server) = serve("127.0.0.1:0".parse().unwrap(), stats.clone()).unwrap();
+		tokio::spawn(server);
+
+		let resp = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+		assert_eq!(resp.status(), reqwest::StatusCode::OK);
+		assert_eq!(resp.headers()["content-type"], "text/html; charset=utf-8");
+		let html = resp.text().await.unwrap();
+		assert!(html.starts_with("<!DOCTYPE html>"));
+		assert!(html.contains("<tr><td>spam</td><td>spam msg/sec</td><td class=\"value\">20.00</td>"));
+		assert!(html.contains("<td>total</td>"));
+		// Counters left out by --counters are not shown, like in /metrics
+		assert!(!html.contains("<td>ham</td>"));
+
+		let resp = reqwest::get(format!("http://{}/counters", addr)).await.unwrap();
+		assert_eq!(resp.headers()["content-type"], "application/json");
+		let counters: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+		let names = counters
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|c| c["name"].as_str().unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(names, ["spam", "total"]);
+		assert_eq!(counters[0]["window"], serde_json::json!([20.0]));
+		assert_eq!(escape_html("<a & \"b\">"), "&lt;a &amp; &quot;b&quot;&gt;");
+	}
+
+	#[tokio::test]
+	async fn pause_test() {
+		let mut stat = RspamdStat::new(4, false);
+		stat.ingest(&serde_json::json!({"scanned": 42, "actions": {}}), Duration::from_secs(1))
+			.unwrap();
+		let stats = SharedStat::new(stat);
+		let (addr, server) = serve("127.0.0.1:0".parse().unwrap(), stats.clone()).unwrap();
+		tokio::spawn(server);
+		let client = reqwest::Client::new();
+		let metrics = format!("http://{}/metrics", addr);
+		let pause = format!("http://{}/admin/pause", addr);
+
+		assert_eq!(client.get(&pause).send().await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+		assert_eq!(client.post(&pause).send().await.unwrap().text().await.unwrap(), "paused\n");
+		let resp = client.get(&metrics).send().await.unwrap();
+		assert_eq!(resp.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+		assert!(!resp.text().await.unwrap().contains("rspamd_mon_scanned_total"));
+
+		// Collection goes on while paused
+		let mut stat = RspamdStat::new(4, false);
+		stat.ingest(&serde_json::json!({"scanned": 43, "actions": {}}), Duration::from_secs(1))
+			.unwrap();
+		stats.publish(stat);
+
+		assert_eq!(client.post(&pause).send().await.unwrap().text().await.unwrap(), "resumed\n");
+		let resp = client.get(&metrics).send().await.unwrap();
+		assert_eq!(resp.status(), reqwest::StatusCode::OK);
+		assert!(resp.text().await.unwrap().contains("rspamd_mon_scanned_total 43\n"));
+	}
+
+	#[tokio::test]
+	async fn gzip_test() {
+		assert!(accepts_gzip("gzip, deflate"));
+		assert!(accepts_gzip("deflate;q=1.0, GZIP;q=0.5"));
+		assert!(!accepts_gzip("deflate, br"));
+		assert!(!accepts_gzip("gzip;q=0"));
+
+		let mut stat = RspamdStat::new(4, false);
+		stat.ingest(&serde_json::json!({"scanned": 42, "learned": 1, "actions": {}}), Duration::from_secs(1))
+			.unwrap();
+		let stats = SharedStat::new(stat);
+		let (addr, server) = serve("127.0.0.1:0".parse().unwrap(), stats.clone()).unwrap();
+		tokio::spawn(server);
+		let client = reqwest::Client::new();
+		let url = format!("http://{}/metrics", addr);
+
+		let plain = client.get(&url).send().await.unwrap();
+		assert!(plain.headers().get("content-encoding").is_none());
+		let plain = plain.text().await.unwrap();
+		assert!(plain.contains("rspamd_mon_scanned_total 42\n"));
+
+		let compressed = client.get(&url).header("Accept-Encoding", "gzip").send().await.unwrap();
+		assert_eq!(compressed.headers()["content-encoding"], "gzip");
+		let compressed = compressed.bytes().await.unwrap();
+		let mut decompressed = String::new();
+		GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+		assert_eq!(decompressed, plain);
+	}
+
+	#[tokio::test]
+	async fn history_test() {
+		let mut stat = RspamdStat::new(3, false);
+		for (scanned, reject) in [(100, 10), (130, 15), (170, 25), (220, 40)] {
+			let json =
+				serde_json::json!({"scanned": scanned, "actions": {"reject": reject, "no action": scanned - reject}});
+			stat.ingest(&json, Duration::from_secs(1)).unwrap();
+		}
+		let expected = stat.element(&KnownCounter::Spam).unwrap().values.clone();
+		assert_eq!(expected, [5.0, 10.0, 15.0]);
+		stat.selection = Some("spam,ham".parse().unwrap());
+
+		let stats = SharedStat::new(stat);
+		let (addr, server) = serve("127.0.0.1:0".parse().unwrap(), stats.clone()).unwrap();
+		tokio::spawn(server);
+		let get = |query: &str| reqwest::get(format!("http://{}/history?{}", addr, query));
+
+		let json: serde_json::Value =
+			serde_json::from_str(&get("counter=spam").await.unwrap().text().await.unwrap()).unwrap();
+		assert_eq!(json["counter"], "spam");
+		let values = json["values"].as_array().unwrap().iter().map(|v| v.as_f64().unwrap());
+		assert!(values.eq(expected.iter().cloned()));
+
+		let resp = get("counter=spam&format=csv").await.unwrap();
+		assert_eq!(resp.headers()["content-type"], "text/csv");
+		assert_eq!(resp.text().await.unwrap(), "spam\n5\n10\n15\n");
+
+		assert_eq!(get("counter=nope").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+		// Counters left out by --counters are not served, like in /metrics
+		assert_eq!(get("counter=total").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+		assert_eq!(get("format=csv").await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+		assert_eq!(get("counter=spam&format=xml").await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+	}
+}
@@ -0,0 +1,188 @@
+use crate::{
+	prometheus::{to_prometheus, EXPOSITION_CONTENT_TYPE},
+	shared::SharedStat,
+};
+use color_eyre::eyre::eyre;
+use log::debug;
+use reqwest::{header::CONTENT_TYPE, Method, Url};
+use std::time::Duration;
+
+/// Pushes metrics to Prometheus Pushgateways, for runs too short to be scraped
+#[derive(Clone)]
+pub struct Pushgateway {
+	client: reqwest::Client,
+	/// Metrics group on each gateway, e.g. `http://pushgateway:9091/metrics/job/rspamd-mon`
+	groups: Vec<Url>,
+}
+
+impl Pushgateway {
+	/// Pushes to the group of `job` and `grouping` labels on each of `gateways`
+	pub fn new(gateways: &[Url], job: &str, grouping: &[(String, String)]) -> color_eyre::Result<Self> {
+		let groups = gateways
+			.iter()
+			.map(|gateway| group_url(gateway, job, grouping))
+			.collect::<color_eyre::Result<Vec<_>>>()?;
+
+		Ok(Self { client: reqwest::Client::new(), groups })
+	}
+
+	/// Replaces metrics of the group on every gateway, a failing gateway does not stop pushing to the others
+	pub async fn push(&self, body: &str) -> color_eyre::Result<()> {
+		self.send_all(Method::PUT, body).await
+	}
+
+	/// Removes the group from every gateway, so stale metrics are not served after the run
+	pub async fn delete(&self) -> color_eyre::Result<()> {
+		self.send_all(Method::DELETE, "").await
+	}
+
+	/// Pushes the latest published stats every `interval`
+	pub async fn run(self, stats: SharedStat, interval: Duration) {
+		let mut ticks = tokio::time::interval(interval);
+		loop {
+			ticks.tick().await;
+			// Pushing is best effort, like other exports
+			if let Err(e) = self.push(&to_prometheus(&stats.load())).await {
+				debug!("{}", e);
+			}
+		}
+	}
+
+	async fn send_all(&self, method: Method, body: &str) -> color_eyre::Result<()> {
+		let mut first_err = None;
+		for group in &self.groups {
+			let res = self
+				.client
+				.request(method.clone(), group.clone())
+				.header(CONTENT_TYPE, EXPOSITION_CONTENT_TYPE)
+				.body(body.to_owned())
+				.send()
+				.await
+				.and_then(|resp| resp.error_for_status());
+			if let Err(e) = res {
+				first_err.get_or_insert_with(|| eyre!("cannot {} metrics at {}: {}", method, group, e));
+			}
+		}
+
+		first_err.map_or(Ok(()), Err)
+	}
+}
+
+/// Returns the URL of a metrics group, e.g. `/metrics/job/rspamd-mon/instance/mx1`
+pub fn group_url(gateway: &Url, job: &str, grouping: &[(String, String)]) -> color_eyre::Result<Url> {
+	let mut url = gateway.clone();
+	{
+		let mut segments = url
+			.path_segments_mut()
+			.map_err(|_| eyre!("invalid Pushgateway URL: {}", gateway))?;
+		segments.pop_if_empty().extend(["metrics", "job", job]);
+		for (name, value) in grouping {
+			segments.extend([name, value]);
+		}
+	}
+
+	Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		counters::RspamdStat,
+		prometheus::to_prometheus,
+		pushgateway::{group_url, Pushgateway},
+	};
+	use reqwest::Url;
+	use std::{
+		io::{Read, Write},
+		net::{SocketAddr, TcpListener},
+		sync::mpsc,
+		time::Duration,
+	};
+
+	fn content_length(head: &str) -> usize {
+		head.lines()
+			.filter_map(|line| line.split_once(':'))
+			.find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+			.map_or(0, |(_, value)| value.trim().parse().unwrap())
+	}
+
+	/// Accepts requests replying with 200, sends the request line and the body of each of them to the channel
+	fn serve_requests() -> (SocketAddr, mpsc::Receiver<(String, String)>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (tx, rx) = mpsc::channel();
+		std::thread::spawn(move || {
+			for stream in listener.incoming() {
+				let mut stream = stream.unwrap();
+				let mut req = Vec::new();
+				let mut buf = [0_u8; 4096];
+				// Read until the headers and as much of the body as they announce have arrived
+				let (head, body) = loop {
+					let n = stream.read(&mut buf).unwrap();
+					req.extend_from_slice(&buf[..n]);
+					let text = String::from_utf8_lossy(&req).into_owned();
+					match text.split_once("\r\n\r\n") {
+						Some((head, body)) if n == 0 || body.len() >= content_length(head) => {
+							break (head.to_owned(), body.to_owned())
+						},
+						_ if n == 0 => break (text, String::new()),
+						_ => {},
+					}
+				};
+				let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+				let request_line = head.lines().next().unwrap_or_default().to_owned();
+				if tx.send((request_line, body)).is_err() {
+					break;
+				}
+			}
+		});
+		(addr, rx)
+	}
+
+	#[test]
+	fn group_url_test() {
+		let gateway = "http://pushgateway:9091".parse().unwrap();
+		let grouping = [("instance".to_owned(), "mx 1".to_owned())];
+		let url = group_url(&gateway, "rspamd-mon", &grouping).unwrap();
+		assert_eq!(url.as_str(), "http://pushgateway:9091/metrics/job/rspamd-mon/instance/mx%201");
+
+		// A gateway behind a path prefix
+		let gateway = "http://proxy/pushgateway/".parse().unwrap();
+		let url = group_url(&gateway, "rspamd-mon", &[]).unwrap();
+		assert_eq!(url.path(), "/pushgateway/metrics/job/rspamd-mon");
+		assert!(group_url(&"mailto:admin@example.com".parse().unwrap(), "rspamd-mon", &[]).is_err());
+	}
+
+	#[tokio::test]
+	async fn push_test() {
+		let mut stat = RspamdStat::new(4, false);
+		stat.ingest(&serde_json::json!({"scanned": 42, "actions": {}}), Duration::from_secs(1))
+			.unwrap();
+		let body = to_prometheus(&stat);
+
+		let (first, first_rx) = serve_requests();
+		let (second, second_rx) = serve_requests();
+		let gateways = [first, second].map(|addr| format!("http://{}", addr).parse::<Url>().unwrap());
+		let gateway = Pushgateway::new(&gateways, "rspamd-mon", &[]).unwrap();
+
+		gateway.push(&body).await.unwrap();
+		for rx in [&first_rx, &second_rx] {
+			let (request_line, pushed) = rx.recv().unwrap();
+			assert_eq!(request_line, "PUT /metrics/job/rspamd-mon HTTP/1.1");
+			assert_eq!(pushed, body);
+			assert!(pushed.contains("rspamd_mon_scanned_total 42\n"));
+		}
+
+		gateway.delete().await.unwrap();
+		for rx in [&first_rx, &second_rx] {
+			assert_eq!(rx.recv().unwrap(), ("DELETE /metrics/job/rspamd-mon HTTP/1.1".to_owned(), String::new()));
+		}
+
+		// One gateway being down does not stop pushing to the other one
+		let down = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let gateways = [down, first].map(|addr| format!("http://{}", addr).parse::<Url>().unwrap());
+		let gateway = Pushgateway::new(&gateways, "rspamd-mon", &[]).unwrap();
+		assert!(gateway.push(&body).await.unwrap_err().to_string().contains(&down.to_string()));
+		assert_eq!(first_rx.recv().unwrap().1, body);
+	}
+}
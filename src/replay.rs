@@ -0,0 +1,197 @@
+use crate::counters::RspamdStat;
+use chrono::DateTime;
+use color_eyre::eyre::eyre;
+use flate2::read::MultiGzDecoder;
+use log::debug;
+use std::{
+	fs::File,
+	io::{self, BufRead, BufReader, Read},
+	path::Path,
+	time::Duration,
+};
+
+/// Stat snapshot recorded at a specific time
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+	/// Unix time in seconds
+	pub timestamp: f64,
+	pub json: serde_json::Value,
+}
+
+/// Reads newline-delimited stat snapshots, each one having a `timestamp` field
+pub struct SampleReader<R: BufRead> {
+	lines: io::Lines<R>,
+}
+
+impl SampleReader<BufReader<Box<dyn Read + Send>>> {
+	/// Opens a dump, files ending with `.gz` are decompressed on the fly
+	pub fn open(path: &Path) -> io::Result<Self> {
+		let file = File::open(path)?;
+		let input: Box<dyn Read + Send> = if path.extension().is_some_and(|ext| ext == "gz") {
+			Box::new(MultiGzDecoder::new(file))
+		} else {
+			Box::new(file)
+		};
+
+		Ok(SampleReader::new(BufReader::new(input)))
+	}
+}
+
+impl<R: BufRead> SampleReader<R> {
+	pub fn new(input: R) -> Self {
+		Self { lines: input.lines() }
+	}
+}
+
+impl<R: BufRead> Iterator for SampleReader<R> {
+	type Item = color_eyre::Result<Sample>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let line = loop {
+			match self.lines.next()? {
+				Ok(line) if line.trim().is_empty() => continue,
+				Ok(line) => break line,
+				Err(e) => return Some(Err(e.into())),
+			}
+		};
+
+		Some(parse_sample(&line))
+	}
+}
+
+/// Parses a snapshot, the timestamp is either Unix time in seconds or an RFC 3339 string
+fn parse_sample(line: &str) -> color_eyre::Result<Sample> {
+	let json = serde_json::from_str::<serde_json::Value>(line)?;
+	let timestamp = match json.get("timestamp") {
+		Some(serde_json::Value::Number(ts)) => ts.as_f64(),
+		Some(serde_json::Value::String(ts)) => DateTime::parse_from_rfc3339(ts)
+			.ok()
+			.map(|ts| ts.timestamp_millis() as f64 / 1000.0),
+		_ => None,
+	}
+	.filter(|ts| ts.is_finite())
+	.ok_or_else(|| eyre!("snapshot has no valid timestamp"))?;
+
+	Ok(Sample { timestamp, json })
+}
+
+/// Feeds snapshots to a stat one by one, rates are computed from the embedded timestamps
+///
+/// With `speed` set, snapshots are replayed in real time divided by it, otherwise as fast as possible.
+pub struct Replayer<I> {
+	samples: I,
+	speed: Option<f64>,
+	last: Option<f64>,
+}
+
+impl<I> Replayer<I>
+where
+	I: Iterator<Item = color_eyre::Result<Sample>>,
+{
+	pub fn new(samples: impl IntoIterator<IntoIter = I>, speed: Option<f64>) -> Self {
+		Self { samples: samples.into_iter(), speed: speed.filter(|&speed| speed > 0.0), last: None }
+	}
+
+	/// Ingests the next snapshot into `stat`, returning its timestamp, or `None` at the end of the dump
+	pub async fn next(&mut self, stat: &mut RspamdStat) -> Option<color_eyre::Result<f64>> {
+		loop {
+			let sample = match self.samples.next()? {
+				Ok(sample) if !sample.timestamp.is_finite() => {
+					return Some(Err(eyre!("snapshot has no valid timestamp")))
+				},
+				Ok(sample) => sample,
+				Err(e) => return Some(Err(e)),
+			};
+			let elapsed = match self.last {
+				Some(prev) if sample.timestamp <= prev => {
+					debug!("snapshot at {} is not newer than the previous one, skipping", sample.timestamp);
+					continue;
+				},
+				Some(prev) => match Duration::try_from_secs_f64(sample.timestamp - prev) {
+					Ok(elapsed) => elapsed,
+					Err(_) => {
+						return Some(Err(eyre!("snapshot at {} is too far from the previous one", sample.timestamp)))
+					},
+				},
+				// The first snapshot only sets the baseline of rates
+				None => Duration::from_secs(1),
+			};
+
+			if let (Some(speed), Some(_)) = (self.speed, self.last) {
+				match Duration::try_from_secs_f64(elapsed.as_secs_f64() / speed) {
+					Ok(pause) => tokio::time::sleep(pause).await,
+					Err(_) => {
+						return Some(Err(eyre!("cannot wait for snapshot at {} at speed {}", sample.timestamp, speed)))
+					},
+				}
+			}
+			if let Err(e) = stat.ingest(&sample.json, elapsed) {
+				return Some(Err(eyre!("cannot ingest snapshot at {}: {}", sample.timestamp, e)));
+			}
+			self.last = Some(sample.timestamp);
+
+			return Some(Ok(sample.timestamp));
+		}
+	}
+}
+
+/// Feeds all snapshots to `stat`, see `Replayer`; returns the number of ingested snapshots
+pub async fn replay<I>(samples: I, stat: &mut RspamdStat, speed: Option<f64>) -> color_eyre::Result<usize>
+where
+	I: IntoIterator<Item = color_eyre::Result<Sample>>,
+{
+	let mut replayer = Replayer::new(samples, speed);
+	let mut ingested = 0;
+
+	while let Some(res) = replayer.next(stat).await {
+		res?;
+		ingested += 1;
+	}
+
+	Ok(ingested)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		counters::RspamdStat,
+		replay::{replay, Sample, SampleReader},
+	};
+	use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+	use std::io::{BufReader, Write};
+
+	#[tokio::test]
+	async fn replay_gzip_test() {
+		let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+		writeln!(encoder, r#"{{"timestamp":1646128800,"actions":{{"reject":100,"no action":1000}}}}"#).unwrap();
+		writeln!(encoder).unwrap();
+		writeln!(encoder, r#"{{"timestamp":"2022-03-01T10:00:10+00:00","actions":{{"reject":150,"no action":1200}}}}"#)
+			.unwrap();
+		let dump = encoder.finish().unwrap();
+
+		let mut stats = RspamdStat::new(4, false);
+		let reader = SampleReader::new(BufReader::new(MultiGzDecoder::new(&dump[..])));
+		assert_eq!(replay(reader, &mut stats, None).await.unwrap(), 2);
+		// 10 seconds passed between the snapshots
+		assert_eq!(stats.spam_stats.values, [5_f64]);
+		assert_eq!(stats.ham_stats.values, [20_f64]);
+
+		let reader = SampleReader::new(BufReader::new(&b"{\"actions\":{}}\n"[..]));
+		assert!(replay(reader, &mut RspamdStat::new(4, false), None).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn bad_timestamps_test() {
+		let sample = |timestamp: f64| Ok(Sample { timestamp, json: serde_json::json!({"actions": {}}) });
+		let mut stats = RspamdStat::new(4, false);
+		assert!(replay([sample(1.0), sample(f64::NAN)], &mut stats, None).await.is_err());
+		assert!(replay([sample(-1e300), sample(1e300)], &mut stats, None).await.is_err());
+		// Snapshots going back in time are skipped
+		assert_eq!(
+			replay([sample(10.0), sample(5.0), sample(11.0)], &mut stats, None)
+				.await
+				.unwrap(),
+			2
+		);
+	}
+}
@@ -0,0 +1,67 @@
+use crate::counters::RspamdStat;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Stats published by the poller, readers get the latest complete snapshot
+///
+/// The poller updates its own copy and swaps it in when done, so the lock is only held to swap or clone
+/// a pointer and readers never see a half-updated state.
+#[derive(Clone)]
+pub struct SharedStat {
+	current: Arc<RwLock<Arc<RspamdStat>>>,
+}
+
+impl SharedStat {
+	pub fn new(stat: RspamdStat) -> Self {
+		Self { current: Arc::new(RwLock::new(Arc::new(stat))) }
+	}
+
+	/// Returns the latest published snapshot
+	pub fn load(&self) -> Arc<RspamdStat> {
+		self.current.read().unwrap_or_else(PoisonError::into_inner).clone()
+	}
+
+	/// Replaces the snapshot seen by readers
+	pub fn publish(&self, stat: RspamdStat) {
+		*self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(stat);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{counters::RspamdStat, shared::SharedStat};
+	use std::time::Duration;
+
+	#[test]
+	fn consistent_snapshot_test() {
+		let elapsed = Duration::from_millis(1);
+		let shared = SharedStat::new(RspamdStat::new(8, false));
+
+		let readers = (0..4)
+			.map(|_| {
+				let shared = shared.clone();
+				std::thread::spawn(move || {
+					let mut seen = 0;
+					while seen < 500 {
+						let snapshot = shared.load();
+						// Spam and ham grow equally, so a torn state would show different windows
+						assert_eq!(snapshot.spam_stats.values, snapshot.ham_stats.values);
+						assert_eq!(snapshot.total.values.len(), snapshot.spam_stats.values.len());
+						seen = snapshot.spam_stats.pushed;
+					}
+				})
+			})
+			.collect::<Vec<_>>();
+
+		let mut stat = RspamdStat::new(8, false);
+		for tick in 0..=500_u64 {
+			let json = serde_json::json!({"actions": {"reject": tick * tick, "no action": tick * tick}});
+			stat.ingest(&json, elapsed).unwrap();
+			shared.publish(stat.clone());
+		}
+
+		for reader in readers {
+			reader.join().unwrap();
+		}
+		assert_eq!(shared.load().spam_stats.pushed, 500);
+	}
+}
@@ -0,0 +1,166 @@
+use crate::{counters::RspamdStat, prometheus::sanitize_name};
+use color_eyre::eyre::eyre;
+use std::{io, net::SocketAddr};
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
+use tokio::net::{lookup_host, UdpSocket};
+
+/// Socket the gauges are sent over
+enum Transport {
+	Udp(UdpSocket),
+	/// Local collectors reading from a Unix datagram socket
+	#[cfg(unix)]
+	Unix(UnixDatagram),
+}
+
+/// Sends the latest values of all counters as StatsD gauges over UDP or a Unix datagram socket
+pub struct StatsdSender {
+	socket: Transport,
+	prefix: String,
+}
+
+impl StatsdSender {
+	/// Resolves the target once and binds a local socket of the same address family
+	///
+	/// A `unix:/run/statsd.sock` host is a Unix datagram socket, the port is ignored then.
+	pub async fn connect(host: &str, port: u16, prefix: &str) -> color_eyre::Result<Self> {
+		if let Some(path) = host.strip_prefix("unix:") {
+			return Ok(Self { socket: connect_unix(path)?, prefix: prefix.to_owned() });
+		}
+
+		let target = lookup_host((host, port))
+			.await?
+			.next()
+			.ok_or_else(|| eyre!("cannot resolve StatsD host {}", host))?;
+		let local =
+			if target.is_ipv4() { SocketAddr::from(([0, 0, 0, 0], 0)) } else { SocketAddr::from(([0_u16; 8], 0)) };
+		let socket = UdpSocket::bind(local).await?;
+		socket.connect(target).await?;
+
+		Ok(Self { socket: Transport::Udp(socket), prefix: prefix.to_owned() })
+	}
+
+	/// Sends all gauges in a single packet, nothing is sent until counters have values
+	pub async fn send(&self, stat: &RspamdStat) -> io::Result<()> {
+		let lines = statsd_lines(stat, &self.prefix);
+		if lines.is_empty() {
+			return Ok(());
+		}
+
+		let packet = lines.join("\n");
+		match &self.socket {
+			Transport::Udp(socket) => socket.send(packet.as_bytes()).await?,
+			#[cfg(unix)]
+			Transport::Unix(socket) => socket.send(packet.as_bytes()).await?,
+		};
+
+		Ok(())
+	}
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> color_eyre::Result<Transport> {
+	let socket = UnixDatagram::unbound()?;
+	socket
+		.connect(path)
+		.map_err(|e| eyre!("cannot connect to StatsD socket {}: {}", path, e))?;
+
+	Ok(Transport::Unix(socket))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(path: &str) -> color_eyre::Result<Transport> {
+	Err(eyre!("unix sockets are not supported on this platform: {}", path))
+}
+
+/// Returns a `prefix.counter:value|g` line for each counter with values
+pub fn statsd_lines(stat: &RspamdStat, prefix: &str) -> Vec<String> {
+	stat.elements()
+		.into_iter()
+		.filter_map(|elt| elt.values.back().map(|value| (sanitize_name(elt.kind.name()), value)))
+		.map(|(name, value)| match prefix {
+			"" => format!("{}:{}|g", name, value),
+			_ => format!("{}.{}:{}|g", prefix, name, value),
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		counters::RspamdStat,
+		statsd::{statsd_lines, StatsdSender},
+	};
+	use std::time::Duration;
+	use tokio::net::UdpSocket;
+
+	#[tokio::test]
+	async fn statsd_test() {
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		assert!(statsd_lines(&stats, "rspamd").is_empty());
+
+		stats
+			.ingest(&serde_json::json!({"actions": {"reject": 100, "no action": 1000}}), elapsed)
+			.unwrap();
+		stats
+			.ingest(&serde_json::json!({"actions": {"reject": 112, "no action": 1015}}), elapsed)
+			.unwrap();
+		let lines = statsd_lines(&stats, "rspamd");
+		assert!(lines.contains(&"rspamd.spam:12|g".to_owned()));
+		assert!(lines.contains(&"rspamd.total:27|g".to_owned()));
+		assert!(statsd_lines(&stats, "").contains(&"ham:15|g".to_owned()));
+
+		let mut derived = RspamdStat::new(4, false);
+		derived.register_derived(|_, _| Some(("spam/min ratio".to_owned(), 0.5)));
+		derived
+			.ingest(&serde_json::json!({"actions": {"reject": 100}}), elapsed)
+			.unwrap();
+		assert!(statsd_lines(&derived, "rspamd").contains(&"rspamd.spam_min_ratio:0.5|g".to_owned()));
+
+		let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let port = receiver.local_addr().unwrap().port();
+		let sender = StatsdSender::connect("127.0.0.1", port, "mail.rspamd").await.unwrap();
+		sender.send(&stats).await.unwrap();
+
+		let mut buf = [0; 1500];
+		let len = receiver.recv(&mut buf).await.unwrap();
+		let packet = std::str::from_utf8(&buf[..len]).unwrap();
+		assert_eq!(packet.lines().count(), lines.len());
+		assert!(packet.lines().any(|line| line == "mail.rspamd.spam:12|g"));
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn unix_socket_test() {
+		use tokio::net::UnixDatagram;
+
+		let elapsed = Duration::from_secs(1);
+		let mut stats = RspamdStat::new(4, false);
+		for reject in [100, 112] {
+			stats
+				.ingest(&serde_json::json!({"actions": {"reject": reject}}), elapsed)
+				.unwrap();
+		}
+
+		let socket = std::env::temp_dir().join(format!("rspamd-mon-{}-statsd.sock", std::process::id()));
+		let _ = std::fs::remove_file(&socket);
+		let receiver = UnixDatagram::bind(&socket).unwrap();
+		let sender = StatsdSender::connect(&format!("unix:{}", socket.display()), 8125, "rspamd")
+			.await
+			.unwrap();
+		sender.send(&stats).await.unwrap();
+
+		let mut buf = [0; 1500];
+		let len = receiver.recv(&mut buf).await.unwrap();
+		let packet = std::str::from_utf8(&buf[..len]).unwrap();
+		assert!(packet.lines().any(|line| line == "rspamd.spam:12|g"));
+		let _ = std::fs::remove_file(&socket);
+
+		let err = StatsdSender::connect("unix:/nonexistent/statsd.sock", 8125, "rspamd")
+			.await
+			.err()
+			.unwrap();
+		assert!(err.to_string().contains("cannot connect to StatsD socket"));
+	}
+}